@@ -1,91 +1,309 @@
 //! Support for archives using `libarchive`.
 
-use std::ffi::{CStr, CString};
+mod libarchive;
+
+use std::cmp::Ordering;
+use std::ffi::CString;
+use std::io::{self, Read};
+use std::ops::RangeInclusive;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 
-use libarchive3_sys::ffi;
+use bytesize::ByteSize;
 
-pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<impl Iterator<Item = Entry>> {
-    let entries = unsafe {
-        let archive = ffi::archive_read_new();
-        if archive.is_null() {
-            anyhow::bail!("archive_read_new failed");
-        }
+use crate::sniff::{self, Kind};
+use libarchive::{Archive, Source};
 
-        ffi::archive_read_support_filter_all(archive);
-        ffi::archive_read_support_format_all(archive);
+/// Limits for the data read from an archive.
+#[derive(Copy, Clone)]
+pub struct Limits {
+    /// Maximum size of every entry.
+    pub max_entry_size: u64,
 
-        ArchiveEntries { archive }
-    };
+    /// Maximum size of every video entry.
+    pub max_video_size: u64,
 
-    let res = unsafe {
-        let cstr = CString::new(path.as_ref().as_os_str().as_bytes())?;
-        ffi::archive_read_open_filename(entries.archive, cstr.as_ptr(), 16 * 1024)
-    };
+    /// Maximum size of all entries in the archive.
+    pub max_total_size: u64,
+}
 
-    if res != ffi::ARCHIVE_OK {
-        anyhow::bail!("File is not an archive");
-    }
+/// Open the archive in the file `path`.
+///
+/// `passwords` are used to decrypt the entries. libarchive tries all of them
+/// until one works.
+pub fn open(path: &Path, limits: Limits, passwords: &[String]) -> anyhow::Result<ArchiveEntries> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    ArchiveEntries::new(Source::File(path), limits, passwords)
+}
+
+/// Open an archive from its contents, like an archive inside another one.
+pub fn open_memory(
+    data: Vec<u8>,
+    limits: Limits,
+    passwords: &[String],
+) -> anyhow::Result<ArchiveEntries> {
+    ArchiveEntries::new(Source::Memory(data.into()), limits, passwords)
+}
 
-    Ok(entries)
+/// Compare the names of two entries in natural order, so `page2.png` is
+/// before `page10.png`.
+///
+/// Different names are never equal, like `page1.png` and `page01.png`.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    natord::compare(a, b).then_with(|| a.cmp(b))
 }
 
 pub struct Entry {
     pub name: String,
-    pub data: Vec<u8>,
+
+    /// Contents of the entry, or the reason why it was skipped.
+    pub data: anyhow::Result<Vec<u8>>,
 }
 
-struct ArchiveEntries {
-    archive: *mut ffi::Struct_archive,
+pub struct ArchiveEntries {
+    archive: Archive,
+
+    limits: Limits,
+
+    /// Glob pattern for the names of the entries to read.
+    filter: Option<glob::Pattern>,
+
+    /// Positions of the entries to read, starting at 1.
+    range: Option<RangeInclusive<usize>>,
+
+    /// Names of the entries found until now, in natural order, to compute
+    /// their positions. Entries not matching the filter are not included.
+    names: Vec<String>,
+
+    /// Bytes read from all entries.
+    total_size: u64,
+
+    /// Number of regular files found until now.
+    files: usize,
+
+    /// Set when the headers can't be read, to stop the iterator.
+    failed: bool,
 }
 
+/// Iterate over the entries in the archive.
+///
+/// Errors reading the contents of an entry are reported in `Entry::data`.
+/// If a header can't be read, the error is returned and the iteration
+/// stops.
 impl Iterator for ArchiveEntries {
-    type Item = Entry;
+    type Item = anyhow::Result<Entry>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
         loop {
-            let mut entry = std::ptr::null_mut();
+            let mut entry = match self.archive.next_entry()? {
+                Ok(entry) => entry,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e.into()));
+                }
+            };
 
-            let res = unsafe { ffi::archive_read_next_header(self.archive, &mut entry) };
-            if res != ffi::ARCHIVE_OK {
-                return None;
+            if !entry.is_regular_file() {
+                continue;
             }
 
-            // Skip non-regular files.
-            let res = unsafe { ffi::archive_entry_filetype(entry) };
-            if res != ffi::AE_IFREG {
+            self.files += 1;
+
+            // Entries without a name can't be sorted, so they are only
+            // reported.
+            let Some(name) = entry.name() else {
+                return Some(Ok(Entry {
+                    name: format!("(entry {})", self.files),
+                    data: Err(anyhow::anyhow!("Entry has no name")),
+                }));
+            };
+
+            if entry.size() == Some(0) || is_text_file(&name) {
                 continue;
             }
 
-            // Get size of the entry.
-            let file_size = usize::try_from(unsafe { ffi::archive_entry_size(entry) }).unwrap_or(0);
+            // Read the first bytes, to skip files that can't be rendered
+            // without reading the rest of their contents. If the size is
+            // unknown, the entry is read until it exceeds the limits.
+            let mut data = Vec::new();
+            let head = match entry.size() {
+                Some(size) => {
+                    data.resize(size.min(sniff::HEAD_SIZE) as usize, 0);
+                    read_data(&mut entry, &mut data)
+                }
+
+                None => {
+                    let max_size = self.limits.max_entry_size.max(self.limits.max_video_size);
+                    read_to_end(&mut entry, &mut data, max_size + 1)
+                }
+            };
+
+            let file_size = entry.size().unwrap_or(data.len() as u64);
             if file_size == 0 {
                 continue;
             }
 
-            // Get name of the entry.
-            let c_name = unsafe { CStr::from_ptr(ffi::archive_entry_pathname(entry)).to_bytes() };
-            let name = String::from_utf8_lossy(c_name).into_owned();
+            let kind = head.is_ok().then(|| sniff::sniff(&data));
 
-            // Get contents of the entry.
-            let mut data: Vec<u8> = vec![0; file_size];
-            let res = unsafe {
-                ffi::archive_read_data(self.archive, data.as_mut_ptr().cast(), data.len())
-            };
+            // Nested archives are read even if they don't match the filter,
+            // since it is applied to their members.
+            let matches_filter = self.filter.as_ref().is_none_or(|f| f.matches(&name));
+            match kind {
+                Some(Kind::Unknown) => continue,
+                Some(Kind::Archive) => (),
+                Some(_) if !matches_filter => continue,
+                _ => (),
+            }
+
+            // Only the entries matching the filter are numbered, so the
+            // others can't be selected by their position.
+            if matches_filter {
+                if !add_name(&mut self.names, self.range.as_ref(), &name) {
+                    continue;
+                }
+            } else if self.range.is_some() {
+                continue;
+            }
 
-            if res as usize != data.len() {
-                return None;
+            if let Err(e) = head {
+                return Some(Ok(Entry { name, data: Err(e) }));
             }
 
-            return Some(Entry { name, data });
+            // Check the limits before allocating memory for the contents.
+            if let Err(e) = check_limits(&self.limits, self.total_size, file_size, kind) {
+                return Some(Ok(Entry { name, data: Err(e) }));
+            }
+
+            let head_size = data.len();
+            data.resize(file_size as usize, 0);
+            let data = read_data(&mut entry, &mut data[head_size..]).map(|_| data);
+
+            self.total_size += file_size;
+
+            return Some(Ok(Entry { name, data }));
         }
     }
 }
 
-impl Drop for ArchiveEntries {
-    fn drop(&mut self) {
-        unsafe { ffi::archive_read_free(self.archive) };
-        self.archive = std::ptr::null_mut();
+impl ArchiveEntries {
+    fn new(source: Source, limits: Limits, passwords: &[String]) -> anyhow::Result<ArchiveEntries> {
+        // libarchive rejects empty passwords.
+        let passwords: Vec<_> = passwords
+            .iter()
+            .filter(|p| !p.is_empty())
+            .map(|p| CString::new(p.as_str()))
+            .collect::<Result<_, _>>()?;
+
+        Ok(ArchiveEntries {
+            archive: Archive::open(&source, &passwords)?,
+            limits,
+            filter: None,
+            range: None,
+            names: Vec::new(),
+            total_size: 0,
+            files: 0,
+            failed: false,
+        })
+    }
+
+    /// Read only the entries with a name matching `filter`.
+    pub fn with_filter(mut self, filter: Option<glob::Pattern>) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Read only the entries in `range` of positions.
+    ///
+    /// The final positions are known only after reading all the entries, so
+    /// the iterator can return entries before the start of the range. They
+    /// have to be checked with `in_range`.
+    pub fn with_range(mut self, range: Option<RangeInclusive<usize>>) -> Self {
+        self.range = range;
+        self
+    }
+
+    /// Returns `true` if the position of the entry is in the range.
+    pub fn in_range(&self, name: &str) -> bool {
+        let Some(range) = &self.range else {
+            return true;
+        };
+
+        self.names
+            .binary_search_by(|n| natural_cmp(n, name))
+            .is_ok_and(|index| range.contains(&(index + 1)))
+    }
+}
+
+/// Add the name of an entry to `names`, to compute the positions.
+///
+/// Returns `false` if the entry must be skipped, because the name is
+/// duplicated, or because it is after the end of the range.
+fn add_name(names: &mut Vec<String>, range: Option<&RangeInclusive<usize>>, name: &str) -> bool {
+    let index = match names.binary_search_by(|n| natural_cmp(n, name)) {
+        // Only the first entry with a name is read.
+        Ok(_) => return false,
+        Err(index) => index,
+    };
+
+    names.insert(index, name.to_owned());
+
+    // Positions can only increase with the next entries, so an entry after
+    // the end of the range is never selected.
+    range.is_none_or(|range| index < *range.end())
+}
+
+/// Fill `buf` with the next bytes of an entry.
+fn read_data(entry: &mut libarchive::Entry, buf: &mut [u8]) -> anyhow::Result<()> {
+    match entry.read_exact(buf) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => anyhow::bail!("Entry is truncated"),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read the contents of an entry with an unknown size, up to `limit` bytes.
+fn read_to_end(entry: &mut libarchive::Entry, buf: &mut Vec<u8>, limit: u64) -> anyhow::Result<()> {
+    entry.take(limit).read_to_end(buf)?;
+    Ok(())
+}
+
+fn check_limits(
+    limits: &Limits,
+    total_size: u64,
+    file_size: u64,
+    kind: Option<Kind>,
+) -> anyhow::Result<()> {
+    let max_size = match kind {
+        Some(Kind::Video) => limits.max_video_size,
+        _ => limits.max_entry_size,
+    };
+
+    if file_size > max_size {
+        anyhow::bail!(
+            "Entry exceeds the maximum size ({} > {})",
+            ByteSize(file_size),
+            ByteSize(max_size)
+        );
+    }
+
+    if total_size + file_size > limits.max_total_size {
+        anyhow::bail!(
+            "Archive exceeds the maximum total size ({})",
+            ByteSize(limits.max_total_size)
+        );
     }
+
+    Ok(())
+}
+
+/// Returns `true` if the name of the entry has an extension for text files,
+/// like `.txt` or `.xml`.
+fn is_text_file(name: &str) -> bool {
+    mime_guess::from_path(name)
+        .first()
+        .is_some_and(|mime| mime.type_() == mime_guess::mime::TEXT)
 }
@@ -0,0 +1,541 @@
+//! Safe wrapper for the functions of `libarchive` used to read archives.
+
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::fmt;
+use std::io::{self, Read};
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+use libarchive3_sys::ffi;
+
+/// Error number set by libarchive when the format is not supported.
+const ARCHIVE_ERRNO_FILE_FORMAT: c_int = libc::EILSEQ;
+
+/// Attempts to repeat an operation when libarchive returns `ARCHIVE_RETRY`.
+const MAX_RETRIES: usize = 3;
+
+/// Block size to read archives from files.
+const BLOCK_SIZE: usize = 16 * 1024;
+
+// Functions missing in `libarchive3_sys`.
+extern "C" {
+    fn archive_entry_is_encrypted(entry: *mut ffi::Struct_archive_entry) -> c_int;
+    fn archive_read_has_encrypted_entries(archive: *mut ffi::Struct_archive) -> c_int;
+    fn archive_read_add_passphrase(
+        archive: *mut ffi::Struct_archive,
+        passphrase: *const c_char,
+    ) -> c_int;
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The format of the archive is not supported.
+    Unsupported(String),
+
+    /// The archive or the entry is encrypted, and there is no valid password.
+    Encrypted(String),
+
+    /// The data of the archive is not valid.
+    Corrupt(String),
+
+    /// Other errors, like failing to read the file.
+    Other(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Unsupported(msg) => write!(f, "Unsupported archive: {msg}"),
+            Error::Encrypted(msg) => write!(f, "Encrypted archive: {msg}"),
+            Error::Corrupt(msg) => write!(f, "Corrupt archive: {msg}"),
+            Error::Other(msg) => write!(f, "Cannot read archive: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Where the archive is read from.
+#[derive(Clone)]
+pub enum Source {
+    File(CString),
+    Memory(Arc<[u8]>),
+}
+
+/// Archive opened for reading.
+pub struct Archive {
+    raw: NonNull<ffi::Struct_archive>,
+
+    /// Keep the buffer of archives read from memory as long as the archive.
+    _source: Source,
+}
+
+impl Archive {
+    /// Open an archive. `passwords` are used to decrypt the entries.
+    pub fn open(source: &Source, passwords: &[CString]) -> Result<Archive, Error> {
+        let raw = NonNull::new(unsafe { ffi::archive_read_new() })
+            .ok_or_else(|| Error::Other("archive_read_new failed".into()))?;
+
+        // Create the value before opening, so the archive is released on
+        // errors.
+        let archive = Archive {
+            raw,
+            _source: source.clone(),
+        };
+
+        for password in passwords {
+            if unsafe { archive_read_add_passphrase(archive.raw(), password.as_ptr()) }
+                != ffi::ARCHIVE_OK
+            {
+                return Err(Error::Other(archive.error_string()));
+            }
+        }
+
+        let res = unsafe {
+            ffi::archive_read_support_filter_all(archive.raw());
+            ffi::archive_read_support_format_all(archive.raw());
+
+            match source {
+                Source::File(path) => {
+                    ffi::archive_read_open_filename(archive.raw(), path.as_ptr(), BLOCK_SIZE)
+                }
+
+                // libarchive doesn't modify the buffer.
+                Source::Memory(data) => ffi::archive_read_open_memory(
+                    archive.raw(),
+                    data.as_ptr().cast_mut().cast(),
+                    data.len(),
+                ),
+            }
+        };
+
+        if res != ffi::ARCHIVE_OK {
+            let message = archive.error_string();
+            return Err(match unsafe { ffi::archive_errno(archive.raw()) } {
+                ARCHIVE_ERRNO_FILE_FORMAT => Error::Unsupported(message),
+                _ => Error::Other(message),
+            });
+        }
+
+        Ok(archive)
+    }
+
+    /// Read the header of the next entry. Returns `None` at the end of the
+    /// archive.
+    ///
+    /// Warnings from libarchive are ignored, since the header is still
+    /// valid.
+    pub fn next_entry(&mut self) -> Option<Result<Entry<'_>, Error>> {
+        let mut retries = 0;
+
+        loop {
+            let mut entry = std::ptr::null_mut();
+
+            match unsafe { ffi::archive_read_next_header(self.raw(), &mut entry) } {
+                ffi::ARCHIVE_OK | ffi::ARCHIVE_WARN => (),
+
+                ffi::ARCHIVE_EOF => return None,
+
+                ffi::ARCHIVE_RETRY if retries < MAX_RETRIES => {
+                    retries += 1;
+                    continue;
+                }
+
+                _ => {
+                    let message = self.error_string();
+                    let encrypted = unsafe { archive_read_has_encrypted_entries(self.raw()) };
+                    return Some(Err(if encrypted > 0 {
+                        Error::Encrypted(message)
+                    } else {
+                        Error::Corrupt(message)
+                    }));
+                }
+            }
+
+            let entry = NonNull::new(entry)?;
+            return Some(Ok(Entry {
+                archive: self,
+                raw: entry,
+            }));
+        }
+    }
+
+    fn raw(&self) -> *mut ffi::Struct_archive {
+        self.raw.as_ptr()
+    }
+
+    /// Message of the last error in the archive.
+    fn error_string(&self) -> String {
+        let message = unsafe { ffi::archive_error_string(self.raw()) };
+        if message.is_null() {
+            return "Unknown error".into();
+        }
+
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+impl Drop for Archive {
+    fn drop(&mut self) {
+        unsafe { ffi::archive_read_free(self.raw()) };
+    }
+}
+
+/// Current entry of an archive. Its contents are read with the `Read`
+/// implementation.
+///
+/// The holes of sparse entries are filled with zeros by libarchive, so the
+/// contents have the size returned by `size`.
+pub struct Entry<'a> {
+    archive: &'a mut Archive,
+    raw: NonNull<ffi::Struct_archive_entry>,
+}
+
+impl Entry<'_> {
+    /// Name of the entry. Returns `None` if the archive has no name for it.
+    pub fn name(&self) -> Option<String> {
+        let name = unsafe { ffi::archive_entry_pathname(self.raw()) };
+        if name.is_null() {
+            return None;
+        }
+
+        let name = unsafe { CStr::from_ptr(name) };
+        Some(name.to_string_lossy().into_owned())
+    }
+
+    /// Size of the contents, if it is known.
+    pub fn size(&self) -> Option<u64> {
+        if unsafe { ffi::archive_entry_size_is_set(self.raw()) } == 0 {
+            return None;
+        }
+
+        u64::try_from(unsafe { ffi::archive_entry_size(self.raw()) }).ok()
+    }
+
+    pub fn is_regular_file(&self) -> bool {
+        unsafe { ffi::archive_entry_filetype(self.raw()) == ffi::AE_IFREG }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        unsafe { archive_entry_is_encrypted(self.raw()) > 0 }
+    }
+
+    fn raw(&self) -> *mut ffi::Struct_archive_entry {
+        self.raw.as_ptr()
+    }
+}
+
+impl Read for Entry<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut retries = 0;
+
+        loop {
+            let res = unsafe {
+                ffi::archive_read_data(self.archive.raw(), buf.as_mut_ptr().cast(), buf.len())
+            };
+
+            if res >= 0 {
+                return Ok(res as usize);
+            }
+
+            let retry = res == ffi::ARCHIVE_WARN as isize || res == ffi::ARCHIVE_RETRY as isize;
+            if retry && retries < MAX_RETRIES {
+                retries += 1;
+                continue;
+            }
+
+            let message = self.archive.error_string();
+            let error = if self.is_encrypted() {
+                Error::Encrypted(message)
+            } else {
+                Error::Corrupt(message)
+            };
+
+            return Err(io::Error::other(error));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Size of the blocks in tar archives.
+    const TAR_BLOCK: usize = 512;
+
+    fn open(data: Vec<u8>) -> Archive {
+        Archive::open(&Source::Memory(data.into()), &[]).unwrap()
+    }
+
+    /// Read the names of the entries, until the end or the first error.
+    fn read_names(archive: &mut Archive) -> Result<Vec<String>, Error> {
+        let mut names = Vec::new();
+        while let Some(entry) = archive.next_entry() {
+            names.push(entry?.name().unwrap());
+        }
+
+        Ok(names)
+    }
+
+    /// Entry of a tar archive, with a ustar header and the contents padded
+    /// to the block size.
+    fn tar_entry(name: &str, typeflag: u8, data: &[u8]) -> Vec<u8> {
+        let mut header = [0; TAR_BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+        header[136..147].copy_from_slice(b"00000000000");
+        header[156] = typeflag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+
+        // The checksum is computed with its own field filled with spaces.
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().copied().map(u32::from).sum();
+        header[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+
+        let mut entry = header.to_vec();
+        entry.extend_from_slice(data);
+        entry.resize(entry.len().next_multiple_of(TAR_BLOCK), 0);
+        entry
+    }
+
+    /// Two empty blocks, at the end of tar archives.
+    fn tar_end() -> Vec<u8> {
+        vec![0; 2 * TAR_BLOCK]
+    }
+
+    /// Block that is not a valid tar header.
+    fn damaged_block() -> Vec<u8> {
+        vec![0x55; TAR_BLOCK]
+    }
+
+    /// Write an archive in memory with libarchive. `write` adds the entries.
+    fn write_archive(
+        set_format: unsafe extern "C" fn(*mut ffi::Struct_archive) -> c_int,
+        write: impl FnOnce(*mut ffi::Struct_archive),
+    ) -> Vec<u8> {
+        let mut buffer = vec![0; 1 << 20];
+        let mut used = 0;
+
+        unsafe {
+            let archive = ffi::archive_write_new();
+            assert_eq!(set_format(archive), ffi::ARCHIVE_OK);
+            ffi::archive_write_set_bytes_in_last_block(archive, 1);
+
+            let res = ffi::archive_write_open_memory(
+                archive,
+                buffer.as_mut_ptr().cast(),
+                buffer.len(),
+                &mut used,
+            );
+            assert_eq!(res, ffi::ARCHIVE_OK);
+
+            write(archive);
+
+            assert_eq!(ffi::archive_write_close(archive), ffi::ARCHIVE_OK);
+            ffi::archive_write_free(archive);
+        }
+
+        buffer.truncate(used);
+        buffer
+    }
+
+    /// Add a regular file to an archive opened with `write_archive`.
+    ///
+    /// `sparse` are the ranges of `size` stored in the archive, as
+    /// `(offset, length)`. `data` is the contents of these ranges.
+    fn write_entry(
+        archive: *mut ffi::Struct_archive,
+        name: &str,
+        size: usize,
+        sparse: &[(usize, usize)],
+        data: &[u8],
+    ) {
+        let name = CString::new(name).unwrap();
+
+        unsafe {
+            let entry = ffi::archive_entry_new();
+            ffi::archive_entry_set_pathname(entry, name.as_ptr());
+            ffi::archive_entry_set_filetype(entry, ffi::AE_IFREG);
+            ffi::archive_entry_set_perm(entry, 0o644);
+            ffi::archive_entry_set_size(entry, size as i64);
+
+            for &(offset, length) in sparse {
+                ffi::archive_entry_sparse_add_entry(entry, offset as i64, length as i64);
+            }
+
+            assert_eq!(ffi::archive_write_header(archive, entry), ffi::ARCHIVE_OK);
+
+            let written = ffi::archive_write_data(archive, data.as_ptr().cast(), data.len());
+            assert_eq!(written, data.len() as isize);
+
+            ffi::archive_entry_free(entry);
+        }
+    }
+
+    #[test]
+    fn read_tar() {
+        let mut data = tar_entry("a.txt", b'0', b"hello");
+        data.extend(tar_entry("dir/", b'5', b""));
+        data.extend(tar_end());
+
+        let mut archive = open(data);
+
+        let mut entry = archive.next_entry().unwrap().unwrap();
+        assert_eq!(entry.name().as_deref(), Some("a.txt"));
+        assert_eq!(entry.size(), Some(5));
+        assert!(entry.is_regular_file());
+        assert!(!entry.is_encrypted());
+
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"hello");
+
+        let entry = archive.next_entry().unwrap().unwrap();
+        assert_eq!(entry.name().as_deref(), Some("dir/"));
+        assert!(!entry.is_regular_file());
+
+        assert!(archive.next_entry().is_none());
+    }
+
+    #[test]
+    fn read_zip() {
+        let data = write_archive(ffi::archive_write_set_format_zip, |archive| {
+            write_entry(archive, "a.png", 3, &[], b"abc");
+            write_entry(archive, "b.png", 0, &[], b"");
+        });
+
+        let mut archive = open(data);
+
+        let mut entry = archive.next_entry().unwrap().unwrap();
+        assert_eq!(entry.name().as_deref(), Some("a.png"));
+
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"abc");
+
+        assert_eq!(read_names(&mut archive).unwrap(), ["b.png"]);
+    }
+
+    #[test]
+    fn warnings_are_ignored() {
+        // A malformed pax header is ignored with ARCHIVE_WARN, and the
+        // entry after it is still valid.
+        let mut data = tar_entry("PaxHeader", b'x', b"malformed\n");
+        data.extend(tar_entry("a.txt", b'0', b"hello"));
+        data.extend(tar_end());
+
+        let mut archive = open(data);
+        assert_eq!(read_names(&mut archive).unwrap(), ["a.txt"]);
+    }
+
+    #[test]
+    fn damaged_header_is_retried() {
+        // libarchive returns ARCHIVE_RETRY for every damaged header, and
+        // skips it.
+        let mut data = tar_entry("a.txt", b'0', b"hello");
+        data.extend(damaged_block());
+        data.extend(tar_entry("b.txt", b'0', b"hello"));
+        data.extend(tar_end());
+
+        let mut archive = open(data);
+        assert_eq!(read_names(&mut archive).unwrap(), ["a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn too_many_retries_are_errors() {
+        let mut data = tar_entry("a.txt", b'0', b"hello");
+        for _ in 0..=MAX_RETRIES {
+            data.extend(damaged_block());
+        }
+        data.extend(tar_entry("b.txt", b'0', b"hello"));
+        data.extend(tar_end());
+
+        let mut archive = open(data);
+        assert!(archive.next_entry().unwrap().is_ok());
+        assert!(matches!(archive.next_entry(), Some(Err(Error::Corrupt(_)))));
+    }
+
+    #[test]
+    fn truncated_header_is_error() {
+        // libarchive returns ARCHIVE_FATAL for an incomplete header.
+        let mut data = tar_entry("a.txt", b'0', b"hello");
+        data.extend(&damaged_block()[..100]);
+
+        let mut archive = open(data);
+        assert!(archive.next_entry().unwrap().is_ok());
+        assert!(matches!(archive.next_entry(), Some(Err(Error::Corrupt(_)))));
+    }
+
+    #[test]
+    fn truncated_contents_are_error() {
+        let mut data = tar_entry("a.txt", b'0', &[1; 4 * TAR_BLOCK]);
+        data.truncate(2 * TAR_BLOCK);
+
+        let mut archive = open(data);
+        let mut entry = archive.next_entry().unwrap().unwrap();
+
+        let e = entry.read_to_end(&mut Vec::new()).unwrap_err();
+        let e = e.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert!(matches!(*e, Error::Corrupt(_)));
+    }
+
+    #[test]
+    fn entry_without_name() {
+        let mut data = tar_entry("a.txt", b'0', b"hello");
+        data.extend(tar_end());
+
+        let mut archive = open(data);
+
+        // libarchive returns a null pathname if it is not set.
+        let raw = NonNull::new(unsafe { ffi::archive_entry_new() }).unwrap();
+        let entry = Entry {
+            archive: &mut archive,
+            raw,
+        };
+
+        assert_eq!(entry.name(), None);
+        assert_eq!(entry.size(), None);
+
+        unsafe { ffi::archive_entry_free(raw.as_ptr()) };
+    }
+
+    #[test]
+    fn sparse_entry() {
+        let size = 3 * BLOCK_SIZE;
+        let data = write_archive(ffi::archive_write_set_format_pax, |archive| {
+            write_entry(
+                archive,
+                "a.bin",
+                size,
+                &[(0, 4), (size - 4, 4)],
+                b"abcdwxyz",
+            );
+        });
+
+        let mut archive = open(data);
+        let mut entry = archive.next_entry().unwrap().unwrap();
+        assert_eq!(entry.size(), Some(size as u64));
+
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents).unwrap();
+
+        let mut expected = vec![0; size];
+        expected[..4].copy_from_slice(b"abcd");
+        expected[size - 4..].copy_from_slice(b"wxyz");
+        assert_eq!(contents, expected);
+    }
+
+    #[test]
+    fn empty_password_is_error() {
+        let mut data = tar_entry("a.txt", b'0', b"hello");
+        data.extend(tar_end());
+
+        let passwords = [CString::new("").unwrap()];
+        let archive = Archive::open(&Source::Memory(data.into()), &passwords);
+        assert!(matches!(archive, Err(Error::Other(_))));
+    }
+}
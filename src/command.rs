@@ -0,0 +1,65 @@
+//! Run external programs.
+
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Where a command writes its output.
+pub enum Output<'a> {
+    Stdout,
+    File(&'a Path),
+}
+
+/// Run a command and returns its output if the process terminates successfully.
+pub fn run(cmd: &mut Command, output: Output) -> anyhow::Result<Vec<u8>> {
+    run_with_input(cmd, None, output)
+}
+
+/// Like `run`, but `input` is written to the standard input of the process.
+pub fn run_with_input(
+    cmd: &mut Command,
+    input: Option<&[u8]>,
+    output: Output,
+) -> anyhow::Result<Vec<u8>> {
+    match input {
+        Some(_) => cmd.stdin(Stdio::piped()),
+        None => cmd.stdin(Stdio::null()),
+    };
+
+    cmd.stderr(Stdio::null());
+
+    match output {
+        Output::Stdout => cmd.stdout(Stdio::piped()),
+        Output::File(_) => cmd.stdout(Stdio::null()),
+    };
+
+    let mut child = cmd.spawn()?;
+    let mut data = Vec::with_capacity(4096);
+
+    // The input is written from another thread, so the process is not
+    // blocked if its output is not read. Errors are ignored, since the
+    // process can exit before reading all the input.
+    std::thread::scope(|scope| {
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            scope.spawn(move || {
+                let _ = stdin.write_all(input);
+            });
+        }
+
+        if let Some(stdout) = child.stdout.as_mut() {
+            stdout.read_to_end(&mut data)?;
+        }
+
+        anyhow::Ok(())
+    })?;
+
+    if !child.wait()?.success() {
+        anyhow::bail!("child failed");
+    }
+
+    if let Output::File(path) = output {
+        data = std::fs::read(path)?;
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,58 @@
+//! Render pages of documents with external programs.
+
+use std::path::Path;
+use std::process::Command;
+
+use crate::command::{run, Output};
+
+/// Extensions of the documents supported by MuPDF that can't be identified
+/// by their contents: EPUB, XPS and OXPS are ZIP archives, and FB2 is an XML
+/// file.
+///
+/// CBZ files are not included, so they are read as archives.
+const MUPDF_EXTENSIONS: &[&str] = &["epub", "fb2", "oxps", "xps"];
+
+/// Returns `true` if the file has the extension of a document supported by
+/// MuPDF.
+pub fn has_mupdf_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| MUPDF_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Render a page of a document, scaled to fit in `size`x`size` pixels.
+///
+/// PDF documents are rendered with `pdftoppm` (from Poppler). If it is not
+/// available, or the document is in other format, it uses `mutool` (from
+/// MuPDF).
+///
+/// The page is returned as a PPM image.
+pub fn get_page(path: &Path, page: u32, size: u32) -> anyhow::Result<Vec<u8>> {
+    if !has_mupdf_extension(path) {
+        let page_arg = page.to_string();
+        let pdftoppm = run(
+            Command::new("pdftoppm")
+                .args(["-f", &page_arg, "-l", &page_arg])
+                .args(["-scale-to", &size.to_string()])
+                .arg("-singlefile")
+                .arg(path),
+            Output::Stdout,
+        );
+
+        if pdftoppm.is_ok() {
+            return pdftoppm;
+        }
+    }
+
+    let size = size.to_string();
+    run(
+        Command::new("mutool")
+            .args(["draw", "-q"])
+            .args(["-F", "pnm"])
+            .args(["-o", "-"])
+            .args(["-w", &size, "-h", &size])
+            .arg(path)
+            .arg(page.to_string()),
+        Output::Stdout,
+    )
+}
@@ -1,20 +1,25 @@
 //! Load images with ffmpeg.
 
-use std::io::Read;
+use std::io::Write;
 use std::path::Path;
-use std::process::{Command, Stdio};
+use std::process::Command;
 use std::str;
 
+use crate::command::{run, run_with_input, Output};
+
 /// Default seek to generate thumbnails from a video.
 const DEFAULT_THUMBNAIL_SEEK: f64 = 10.;
 
 pub fn get_frame(path: &Path) -> anyhow::Result<Vec<u8>> {
     // Get duration of the stream.
-    let duration = run(Command::new("ffprobe")
-        .args(["-loglevel", "error"])
-        .args(["-show_entries", "format=duration"])
-        .args(["-print_format", "csv=print_section=0"])
-        .arg(path))?;
+    let duration = run(
+        Command::new("ffprobe")
+            .args(["-loglevel", "error"])
+            .args(["-show_entries", "format=duration"])
+            .args(["-print_format", "csv=print_section=0"])
+            .arg(path),
+        Output::Stdout,
+    )?;
 
     let duration = match str::from_utf8(&duration).map(|s| s.trim().parse::<f64>()) {
         Ok(Ok(d)) => d,
@@ -26,33 +31,83 @@ pub fn get_frame(path: &Path) -> anyhow::Result<Vec<u8>> {
     //
     // Frame is encoded as PPM (lossless, uncompressed) to reduce
     // processing time.
-    let data = run(Command::new("ffmpeg")
-        .args(["-loglevel", "error"])
-        .arg("-ss")
-        .arg(format!("{}", duration * DEFAULT_THUMBNAIL_SEEK / 100.))
-        .arg("-i")
-        .arg(path)
-        .args(["-vframes", "1"])
-        .args(["-c:v", "ppm"])
-        .args(["-f", "image2"])
-        .arg("-"))?;
+    let data = run(
+        Command::new("ffmpeg")
+            .args(["-loglevel", "error"])
+            .arg("-ss")
+            .arg(format!("{}", duration * DEFAULT_THUMBNAIL_SEEK / 100.))
+            .arg("-i")
+            .arg(path)
+            .args(["-vframes", "1"])
+            .args(["-c:v", "ppm"])
+            .args(["-f", "image2"])
+            .arg("-"),
+        Output::Stdout,
+    )?;
 
     Ok(data)
 }
 
-/// Run a command and returns its output if the process terminates successfully.
-fn run(cmd: &mut Command) -> anyhow::Result<Vec<u8>> {
-    cmd.stdin(Stdio::null());
-    cmd.stdout(Stdio::piped());
-    cmd.stderr(Stdio::null());
+/// Get a frame from a video in memory, like a member of an archive.
+///
+/// The video is sent to ffmpeg through a pipe. If ffmpeg needs to seek in
+/// the file to decode it, it is written to a temporary file.
+pub fn get_frame_from_memory(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if needs_seeking(data) {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(data)?;
+        return get_frame(file.path());
+    }
+
+    // The duration is not known before reading the stream, so the frame is
+    // chosen by the `thumbnail` filter from the first frames.
+    run_with_input(
+        Command::new("ffmpeg")
+            .args(["-loglevel", "error"])
+            .args(["-i", "pipe:0"])
+            .args(["-vf", "thumbnail"])
+            .args(["-vframes", "1"])
+            .args(["-c:v", "ppm"])
+            .args(["-f", "image2"])
+            .arg("-"),
+        Some(data),
+        Output::Stdout,
+    )
+}
+
+/// Returns `true` if the video can't be decoded from a pipe.
+///
+/// ISO-BMFF files (MP4, QuickTime) need the `moov` box to decode the frames.
+/// If it is after the `mdat` box, ffmpeg has to seek to read it.
+fn needs_seeking(data: &[u8]) -> bool {
+    if data.get(4..8) != Some(b"ftyp") {
+        return false;
+    }
+
+    let mut pos = 0;
+    while let Some(header) = data.get(pos..pos + 8) {
+        match &header[4..8] {
+            b"moov" => return false,
+            b"mdat" => return true,
+            _ => (),
+        }
 
-    let mut child = cmd.spawn()?;
-    let mut data = Vec::with_capacity(4096);
-    child.stdout.as_mut().unwrap().read_to_end(&mut data)?;
+        // A size of 1 means that the size is stored as a 64-bit value after
+        // the box type. A size of 0 means that the box extends to the end.
+        let size = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            0 => return true,
+            1 => match data.get(pos + 8..pos + 16) {
+                Some(size) => u64::from_be_bytes(size.try_into().unwrap()),
+                None => return true,
+            },
+            size => u64::from(size),
+        };
 
-    if child.wait()?.success() {
-        return Ok(data);
+        match usize::try_from(size) {
+            Ok(size) if size >= 8 => pos = pos.saturating_add(size),
+            _ => return true,
+        }
     }
 
-    anyhow::bail!("child failed");
+    true
 }
@@ -1,16 +1,108 @@
-use image::{DynamicImage, RgbImage};
+#[cfg(feature = "heif")]
+mod heif;
+pub mod icc;
+mod jpeg;
+pub mod limits;
+mod metadata;
+mod preview;
+mod raw;
+#[cfg(feature = "svg")]
+mod svg;
+mod tiff;
+pub mod tonemap;
+
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Rgb, RgbImage, RgbaImage};
+use std::io::{Cursor, Read};
 use std::path::{Path, PathBuf};
 use turbojpeg::Subsamp;
 
+use crate::sniff::Kind;
+use crate::{documents, thumbnailers};
+
 /// Maximum size for image files (32M).
 const DEFAULT_MAX_IMAGE_FILE_SIZE: u64 = 32 << 20;
 
+/// Bytes to read from files that exceed the maximum size, to look for an
+/// embedded preview. EXIF data is limited to a single APP1 segment (64K),
+/// and the MPF segment is usually after it.
+const PREVIEW_HEAD_SIZE: u64 = 128 << 10;
+
+/// Scaling factors (as `1/n`) supported by libjpeg-turbo to reduce the size
+/// of the images while they are decoded, from smaller to larger.
+const JPEG_SCALING_DENOMINATORS: [usize; 4] = [8, 4, 2, 1];
+
+/// Size, in pixels, of the squares in the checkerboard background.
+const CHECKERBOARD_SQUARE: u32 = 8;
+
+/// Colors for the squares in the checkerboard background.
+const CHECKERBOARD_COLORS: [[u8; 3]; 2] = [[0xFF, 0xFF, 0xFF], [0xCC, 0xCC, 0xCC]];
+
+/// Extensions of comic books. They are always rendered as archives.
+const COMIC_BOOK_EXTENSIONS: &[&str] = &["cb7", "cbr", "cbt", "cbz"];
+
+/// Background for images with transparency.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Background {
+    /// Keep the transparency, and send the thumbnail as a PNG image.
+    Transparent,
+
+    /// Fill the background with a checkerboard pattern.
+    Checkerboard,
+
+    /// Fill the background with a solid color.
+    Color([u8; 3]),
+}
+
+/// Options to generate thumbnails.
+#[derive(Copy, Clone)]
+pub struct Options {
+    /// Maximum size, in pixels, of the thumbnail.
+    pub height: u32,
+    pub width: u32,
+
+    pub background: Background,
+
+    /// Rotate or flip the image, according to its EXIF orientation.
+    pub apply_orientation: bool,
+
+    /// Page to render from documents, starting at 1.
+    pub document_page: u32,
+
+    /// Profile to convert the colors of the images. If `None`, images are
+    /// converted to sRGB.
+    pub output_profile: Option<&'static icc::OutputProfile>,
+
+    /// Tonemapping for images with 16-bit or floating-point samples.
+    pub tonemap: tonemap::Tonemap,
+}
+
 pub struct Thumbnail {
     pub height: u32,
     pub width: u32,
     pub pixels: Vec<u8>,
 }
 
+impl Options {
+    /// Returns `true` if an image of `width`x`height` pixels is not smaller
+    /// than its thumbnail.
+    fn is_large_enough(&self, width: u32, height: u32) -> bool {
+        let (thumbnail_width, thumbnail_height) = self.fit(width as f32, height as f32);
+        thumbnail_width <= width && thumbnail_height <= height
+    }
+
+    /// Size of the thumbnail for an image of `width`x`height` pixels, keeping
+    /// its aspect ratio.
+    ///
+    /// The bounds are the same used by `DynamicImage::thumbnail` in
+    /// `thumbnail`, which receives `height` as the maximum width.
+    fn fit(&self, width: f32, height: f32) -> (u32, u32) {
+        let ratio = f32::min(self.height as f32 / width, self.width as f32 / height);
+        let fitted = |size: f32| ((size * ratio).round() as u32).max(1);
+        (fitted(width), fitted(height))
+    }
+}
+
 pub enum Source<'a> {
     Path(PathBuf),
     Mem(&'a [u8], PathBuf),
@@ -32,38 +124,221 @@ impl Source<'_> {
     }
 }
 
-/// Load an image from a file and returns the contents of a thumbnail.
-pub fn thumbnail(
-    source: &Source,
-    height: u32,
-    width: u32,
-    max_size: Option<u64>,
-) -> anyhow::Result<Thumbnail> {
-    let image = match source {
-        Source::Mem(mem, _) => image::load_from_memory(mem)?.into_rgb8(),
-
-        Source::Path(ref path) => {
-            match load_file(path, max_size) {
-                Ok(i) => i,
-                Err(e) => {
-                    // If the file can't be parsed as an image, try to capture a frame
-                    // with ffmpeg.
-                    if let Ok(frame) = crate::ffmpeg::get_frame(path.as_ref()) {
-                        image::load_from_memory(&frame)?.into_rgb8()
-                    } else {
-                        return Err(e);
-                    }
+/// State shared by all threads to load images.
+pub struct Loader {
+    /// Maximum size of the files to read.
+    pub max_file_size: Option<u64>,
+
+    /// Maximum size of the videos read from archives.
+    pub max_video_size: u64,
+
+    /// Maximum size of all the files read from an archive.
+    pub max_archive_size: u64,
+
+    /// Maximum number of nested archives to open.
+    pub max_archive_depth: u32,
+
+    /// Glob pattern for the names of the archive members to render.
+    pub archive_filter: Option<glob::Pattern>,
+
+    /// Passwords for encrypted archives.
+    pub archive_passwords: Vec<String>,
+
+    /// External programs to generate thumbnails.
+    pub thumbnailers: thumbnailers::Registry,
+
+    /// Limits for the size of the decoded images.
+    pub limits: limits::Limits,
+}
+
+/// Image loaded by a `Loader`. The memory reserved for its pixels is
+/// released when it is dropped.
+pub struct Loaded<'a> {
+    pub image: DynamicImage,
+
+    /// Color profile of the image. If it is `None`, the image is in sRGB.
+    profile: Option<Box<qcms::Profile>>,
+
+    reservation: Option<limits::Reservation<'a>>,
+}
+
+impl Loaded<'_> {
+    fn map(self, f: impl FnOnce(DynamicImage) -> DynamicImage) -> Self {
+        Loaded {
+            image: f(self.image),
+            ..self
+        }
+    }
+}
+
+impl From<DynamicImage> for Loaded<'_> {
+    fn from(image: DynamicImage) -> Self {
+        Loaded {
+            image,
+            profile: None,
+            reservation: None,
+        }
+    }
+}
+
+impl Loader {
+    /// Maximum size of the files to read, including the entries of archives
+    /// that are not videos.
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size.unwrap_or(DEFAULT_MAX_IMAGE_FILE_SIZE)
+    }
+
+    /// Returns `true` if a file identified as an archive is rendered as a
+    /// single file.
+    ///
+    /// Some documents (like EPUB or XPS) and office files (like DOCX or ODT)
+    /// are ZIP archives. They are rendered with MuPDF, or with a thumbnailer
+    /// configured for this program. Thumbnailers installed in the system are
+    /// ignored, since they usually accept comic books too.
+    pub fn renders_archive(&self, path: &Path) -> bool {
+        if has_comic_book_extension(path) {
+            return false;
+        }
+
+        documents::has_mupdf_extension(path)
+            || self.thumbnailers.find(path).is_some_and(|t| !t.system)
+    }
+
+    /// Load the full image from a source, and convert its colors to the
+    /// output profile.
+    pub fn load(
+        &self,
+        source: &Source,
+        kind: Kind,
+        options: &Options,
+    ) -> anyhow::Result<Loaded<'_>> {
+        let loaded = self.load_image(source, kind, options)?;
+
+        Ok(Loaded {
+            image: icc::convert(loaded.image, loaded.profile.as_deref(), options),
+            profile: None,
+            reservation: loaded.reservation,
+        })
+    }
+
+    /// Load the full image from a source, using the decoder for its kind.
+    ///
+    /// If the decoder fails, and there is an external thumbnailer for the
+    /// file, the image is generated by the thumbnailer.
+    fn load_image(
+        &self,
+        source: &Source,
+        kind: Kind,
+        options: &Options,
+    ) -> anyhow::Result<Loaded<'_>> {
+        let path = match source {
+            Source::Mem(mem, path) => {
+                let max_size = match kind {
+                    Kind::Video => self.max_video_size,
+                    _ => self.max_file_size(),
+                };
+
+                if mem.len() as u64 > max_size {
+                    anyhow::bail!("File exceeds the maximum size");
                 }
+
+                return match kind {
+                    Kind::Image if raw::has_raw_extension(path) => {
+                        raw::load(*mem, options, max_size, &self.limits)
+                    }
+                    Kind::Image => decode(mem, options, &self.limits),
+                    Kind::Video => crate::ffmpeg::get_frame_from_memory(mem)
+                        .and_then(|frame| decode_pixels(&frame, options, &self.limits)),
+                    _ => anyhow::bail!("Unsupported file type"),
+                };
+            }
+
+            Source::Path(path) => path,
+        };
+
+        let thumbnailer = self.thumbnailers.find(path);
+
+        let ffmpeg_frame = || {
+            crate::ffmpeg::get_frame(path)
+                .and_then(|frame| decode_pixels(&frame, options, &self.limits))
+        };
+
+        let image = match kind {
+            // Some images can't be decoded by the `image` crate, like AVIF
+            // files. ffmpeg may be able to read them.
+            Kind::Image => match load_file(path, options, self.max_file_size(), &self.limits) {
+                Err(e) if thumbnailer.is_none() => ffmpeg_frame().map_err(|_| e),
+                image => image,
+            },
+
+            Kind::Document => self.load_document(path, options),
+
+            // Documents that can't be identified by their contents.
+            Kind::Archive | Kind::Unknown if documents::has_mupdf_extension(path) => {
+                self.load_document(path, options)
+            }
+
+            Kind::Video => ffmpeg_frame(),
+
+            // Some videos can't be identified by their contents, like
+            // QuickTime files without a `ftyp` box. Files with a thumbnailer
+            // are not sent to ffmpeg.
+            Kind::Unknown if thumbnailer.is_none() => {
+                ffmpeg_frame().map_err(|_| anyhow::anyhow!("Unsupported file type"))
+            }
+
+            Kind::Archive | Kind::Unknown => Err(anyhow::anyhow!("Unsupported file type")),
+        };
+
+        match (image, thumbnailer) {
+            (Err(_), Some(thumbnailer)) => {
+                let image = thumbnailer.run(path, options.height.max(options.width))?;
+                decode_pixels(&image, options, &self.limits)
+            }
+
+            (image, _) => image,
+        }
+    }
+
+    fn load_document(&self, path: &Path, options: &Options) -> anyhow::Result<Loaded<'_>> {
+        let size = options.height.max(options.width);
+        let page = documents::get_page(path, options.document_page, size)?;
+        decode_pixels(&page, options, &self.limits)
+    }
+}
+
+/// Returns the contents of a thumbnail for an image.
+///
+/// The thumbnail is encoded as PNG if it keeps the transparent pixels of
+/// the image. Otherwise, it is encoded as JPEG.
+pub fn thumbnail(image: DynamicImage, options: &Options) -> anyhow::Result<Thumbnail> {
+    let thumbnail = image.thumbnail(options.height, options.width);
+    let thumbnail = tonemap::apply(thumbnail, &options.tonemap);
+
+    let thumbnail = if !thumbnail.color().has_alpha() {
+        thumbnail.into_rgb8()
+    } else {
+        let thumbnail = thumbnail.into_rgba8();
+
+        // Many images have an alpha channel without transparent pixels, like
+        // GIF files.
+        if thumbnail.pixels().all(|pixel| pixel[3] == u8::MAX) {
+            DynamicImage::ImageRgba8(thumbnail).into_rgb8()
+        } else {
+            match options.background {
+                Background::Transparent => return encode_png(thumbnail),
+
+                Background::Checkerboard => composite(&thumbnail, |x, y| {
+                    CHECKERBOARD_COLORS
+                        [((x / CHECKERBOARD_SQUARE + y / CHECKERBOARD_SQUARE) % 2) as usize]
+                }),
+
+                Background::Color(color) => composite(&thumbnail, |_, _| color),
             }
         }
     };
 
-    let thumbnail = DynamicImage::ImageRgb8(image).thumbnail(height, width);
-    let buf = turbojpeg::compress_image(
-        thumbnail.as_rgb8().expect("thumbnail must be ImageRgb8"),
-        90,
-        Subsamp::None,
-    )?;
+    let buf = turbojpeg::compress_image(&thumbnail, 90, Subsamp::None)?;
 
     let pixels = buf.as_ref().into();
 
@@ -74,12 +349,69 @@ pub fn thumbnail(
     })
 }
 
-fn load_file<P: AsRef<Path>>(path: &P, max_size: Option<u64>) -> anyhow::Result<RgbImage> {
+fn encode_png(image: RgbaImage) -> anyhow::Result<Thumbnail> {
+    let mut pixels = Vec::new();
+    image.write_to(&mut Cursor::new(&mut pixels), ImageFormat::Png)?;
+
+    Ok(Thumbnail {
+        height: image.height(),
+        width: image.width(),
+        pixels,
+    })
+}
+
+/// Blend an image with transparency over a background, which is computed
+/// by `background` for every pixel.
+fn composite(image: &RgbaImage, background: impl Fn(u32, u32) -> [u8; 3]) -> RgbImage {
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let [r, g, b, a] = image.get_pixel(x, y).0;
+        let bg = background(x, y);
+
+        let blend = |fg: u8, bg: u8| {
+            let a = u32::from(a);
+            ((u32::from(fg) * a + u32::from(bg) * (255 - a) + 127) / 255) as u8
+        };
+
+        Rgb([blend(r, bg[0]), blend(g, bg[1]), blend(b, bg[2])])
+    })
+}
+
+fn has_comic_book_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| COMIC_BOOK_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+fn load_file<'l, P: AsRef<Path>>(
+    path: &P,
+    options: &Options,
+    max_size: u64,
+    limits: &'l limits::Limits,
+) -> anyhow::Result<Loaded<'l>> {
     let metadata = std::fs::metadata(path.as_ref())?;
 
-    let max_size = max_size.unwrap_or(DEFAULT_MAX_IMAGE_FILE_SIZE);
+    // Only the previews of RAW files are read, so the maximum size is
+    // applied to them.
+    if metadata.is_file() && raw::has_raw_extension(path.as_ref()) {
+        let file = std::fs::File::open(path.as_ref())?;
+        return raw::load(&file, options, max_size, limits);
+    }
 
     if metadata.len() > max_size {
+        // The file is too large to be decoded, but it may have a preview.
+        // The segments to find it are in its first bytes.
+        let file = std::fs::File::open(path.as_ref())?;
+        let mut head = Vec::new();
+        (&file).take(PREVIEW_HEAD_SIZE).read_to_end(&mut head)?;
+
+        if let Some(preview) = preview::find_in_file(&file, &head, max_size, options) {
+            let loaded = decode_pixels(&preview, options, limits)?;
+            return Ok(Loaded {
+                profile: icc::embedded_profile(&head),
+                ..loaded.map(|image| orient(image, metadata::orientation(&head), options))
+            });
+        }
+
         anyhow::bail!(
             "File exceeds the maximum size ({} > {})",
             metadata.len(),
@@ -92,14 +424,138 @@ fn load_file<P: AsRef<Path>>(path: &P, max_size: Option<u64>) -> anyhow::Result<
     }
 
     let data = std::fs::read(path.as_ref())?;
+    decode(&data, options, limits)
+}
+
+/// Decode an image from its contents, and apply its orientation.
+///
+/// If the image contains a preview large enough for the thumbnail, it is
+/// used instead of the full image.
+fn decode<'l>(
+    data: &[u8],
+    options: &Options,
+    limits: &'l limits::Limits,
+) -> anyhow::Result<Loaded<'l>> {
+    #[cfg(feature = "heif")]
+    if crate::sniff::is_heif(data) {
+        return heif::decode(data, options, limits);
+    }
+
+    #[cfg(feature = "svg")]
+    if crate::sniff::is_svg(data) {
+        return svg::decode(data, options).map(Loaded::from);
+    }
+
+    // If the preview can't be decoded, use the full image.
+    let preview =
+        preview::find(data, options).and_then(|p| decode_pixels(&p, options, limits).ok());
+    let loaded = match preview {
+        Some(loaded) => loaded,
+        None => decode_pixels(data, options, limits)?,
+    };
+
+    Ok(Loaded {
+        profile: icc::embedded_profile(data),
+        ..loaded.map(|image| orient(image, metadata::orientation(data), options))
+    })
+}
+
+/// Rotate or flip the image, if it is enabled in the options.
+fn orient(
+    mut image: DynamicImage,
+    orientation: Option<Orientation>,
+    options: &Options,
+) -> DynamicImage {
+    if let (true, Some(orientation)) = (options.apply_orientation, orientation) {
+        image.apply_orientation(orientation);
+    }
 
+    image
+}
+
+/// Decode the pixels of an image.
+///
+/// The dimensions in the header are checked against the limits, and the
+/// memory for the pixels is reserved, before decoding them.
+fn decode_pixels<'l>(
+    data: &[u8],
+    options: &Options,
+    limits: &'l limits::Limits,
+) -> anyhow::Result<Loaded<'l>> {
     // If this file is identified as a JPEG, try to load it with turbojpeg. If
     // it fails, fallback to JPEG decoder in the image crate.
     if data.get(0..2) == Some(&[0xFF, 0xD8]) {
-        if let Ok(img) = turbojpeg::decompress_image(&data) {
-            return Ok(img);
+        if let Ok(header) = turbojpeg::read_header(data) {
+            let (width, height) = jpeg_output_size(&header, options);
+            let reservation = limits.reserve(
+                header.width as u32,
+                header.height as u32,
+                3 * width as u64 * height as u64,
+            )?;
+
+            if let Ok(img) = decompress_jpeg(data, options) {
+                return Ok(Loaded {
+                    image: DynamicImage::ImageRgb8(img),
+                    profile: None,
+                    reservation: Some(reservation),
+                });
+            }
         }
     }
 
-    Ok(image::load_from_memory(&data)?.into_rgb8())
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    reader.limits(limits.decoder_limits());
+
+    let decoder = reader.into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    let reservation = limits.reserve(width, height, decoder.total_bytes())?;
+
+    Ok(Loaded {
+        image: DynamicImage::from_decoder(decoder)?,
+        profile: None,
+        reservation: Some(reservation),
+    })
+}
+
+/// Decode a JPEG image with turbojpeg.
+///
+/// The image is scaled down while it is decoded, as much as possible without
+/// making it smaller than the thumbnail.
+fn decompress_jpeg(data: &[u8], options: &Options) -> anyhow::Result<RgbImage> {
+    let mut decompressor = turbojpeg::Decompressor::new()?;
+    let header = decompressor.read_header(data)?;
+
+    // When the output is smaller than the JPEG image, turbojpeg uses the
+    // scaling factor that fits in it.
+    let (width, height) = jpeg_output_size(&header, options);
+    let mut image = turbojpeg::Image {
+        pixels: vec![0; 3 * width * height],
+        width,
+        pitch: 3 * width,
+        height,
+        format: turbojpeg::PixelFormat::RGB,
+    };
+
+    decompressor.decompress(data, image.as_deref_mut())?;
+
+    RgbImage::from_raw(width as u32, height as u32, image.pixels)
+        .ok_or_else(|| anyhow::anyhow!("Invalid image size"))
+}
+
+/// Size of a JPEG image after scaling it down with the largest factor that
+/// doesn't make it smaller than the thumbnail.
+fn jpeg_output_size(header: &turbojpeg::DecompressHeader, options: &Options) -> (usize, usize) {
+    let denominator = JPEG_SCALING_DENOMINATORS
+        .into_iter()
+        .find(|&d| {
+            let width = header.width.div_ceil(d) as u32;
+            let height = header.height.div_ceil(d) as u32;
+            options.is_large_enough(width, height)
+        })
+        .unwrap_or(1);
+
+    (
+        header.width.div_ceil(denominator),
+        header.height.div_ceil(denominator),
+    )
 }
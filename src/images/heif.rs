@@ -0,0 +1,105 @@
+//! Decode HEIF images (HEIC, AVIF) with libheif.
+
+use anyhow::Context;
+use image::{DynamicImage, RgbImage, RgbaImage};
+use libheif_rs::{ColorSpace, DecodingOptions, HeifContext, ImageHandle, LibHeif, RgbChroma};
+use qcms::Profile;
+
+use super::{icc, limits, Loaded, Options};
+
+/// Decode the primary image of a HEIF file.
+///
+/// If the file contains a thumbnail large enough, it is used instead of the
+/// primary image.
+///
+/// libheif applies the transformations (rotation, mirroring, and cropping)
+/// defined in the file, so the EXIF orientation must be ignored.
+///
+/// The dimensions in the header are checked against the limits, and the
+/// memory for the pixels is reserved, before decoding them.
+pub fn decode<'l>(
+    data: &[u8],
+    options: &Options,
+    limits: &'l limits::Limits,
+) -> anyhow::Result<Loaded<'l>> {
+    let lib_heif = LibHeif::new();
+    let context = HeifContext::read_from_bytes(data)?;
+    let primary = context.primary_image_handle()?;
+
+    // Thumbnails may not have their own profile.
+    let profile = color_profile(&primary);
+
+    let handle = find_thumbnail(&primary, options).unwrap_or(primary);
+
+    let mut decoding_options = DecodingOptions::new();
+    if let Some(decoding_options) = &mut decoding_options {
+        decoding_options.set_ignore_transformations(!options.apply_orientation);
+        decoding_options.set_convert_hdr_to_8bit(true);
+    }
+
+    let has_alpha = handle.has_alpha_channel();
+    let chroma = if has_alpha {
+        RgbChroma::Rgba
+    } else {
+        RgbChroma::Rgb
+    };
+
+    let channels = if has_alpha { 4 } else { 3 };
+    let (width, height) = (handle.width(), handle.height());
+    let reservation = limits.reserve(
+        width,
+        height,
+        channels as u64 * u64::from(width) * u64::from(height),
+    )?;
+
+    let image = lib_heif.decode(&handle, ColorSpace::Rgb(chroma), decoding_options)?;
+    let plane = image
+        .planes()
+        .interleaved
+        .context("Missing interleaved plane in HEIF image")?;
+
+    // Copy the rows, without the padding added by libheif.
+    let row_size = plane.width as usize * channels;
+    let mut pixels = Vec::with_capacity(row_size * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(row.get(..row_size).context("Invalid HEIF plane")?);
+    }
+
+    let image = if has_alpha {
+        RgbaImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgba8)
+    } else {
+        RgbImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgb8)
+    };
+
+    Ok(Loaded {
+        image: image.context("Invalid HEIF image size")?,
+        profile,
+        reservation: Some(reservation),
+    })
+}
+
+/// Color profile of the image, from its ICC profile or from its `nclx`
+/// color information.
+fn color_profile(handle: &ImageHandle) -> Option<Box<Profile>> {
+    if let Some(raw) = handle.color_profile_raw() {
+        return icc::parse(&raw.data);
+    }
+
+    let nclx = handle.color_profile_nclx()?;
+    icc::from_cicp(
+        nclx.color_primaries() as u8,
+        nclx.transfer_characteristics() as u8,
+    )
+}
+
+/// Find the smallest thumbnail that is large enough.
+fn find_thumbnail(primary: &ImageHandle, options: &Options) -> Option<ImageHandle> {
+    let mut ids = vec![0; primary.number_of_thumbnails()];
+    let count = primary.thumbnail_ids(&mut ids);
+
+    ids[..count]
+        .iter()
+        .filter_map(|id| primary.thumbnail(*id).ok())
+        .filter(|thumbnail| options.is_large_enough(thumbnail.width(), thumbnail.height()))
+        .min_by_key(|thumbnail| u64::from(thumbnail.width()) * u64::from(thumbnail.height()))
+}
@@ -0,0 +1,177 @@
+//! Color management with the ICC profiles embedded in the images.
+//!
+//! Images are converted to sRGB, or to the profile given in the command
+//! line, before generating the thumbnail.
+
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::Context;
+use image::{DynamicImage, ImageDecoder, ImageReader};
+use qcms::{DataType, Intent, Profile, Transform};
+
+use super::jpeg::{self, MARKER_APP2};
+use super::{tonemap, Options};
+
+/// Identifier of the APP2 segments with an ICC profile.
+const ICC_IDENTIFIER: &[u8] = b"ICC_PROFILE\0";
+
+/// Range in the ICC header for the color space of the profile.
+const COLOR_SPACE_RANGE: std::ops::Range<usize> = 16..20;
+
+/// Profile for the colors of the thumbnails.
+pub struct OutputProfile {
+    /// Contents of the ICC file. Used for the key of the cache.
+    pub data: Vec<u8>,
+
+    profile: Box<Profile>,
+}
+
+impl OutputProfile {
+    pub fn load(path: &Path) -> anyhow::Result<OutputProfile> {
+        let data = std::fs::read(path)?;
+
+        if !is_rgb(&data) {
+            anyhow::bail!("Only RGB profiles are supported");
+        }
+
+        let mut profile = Profile::new_from_slice(&data, false).context("Invalid ICC profile")?;
+        profile.precache_output_transform();
+
+        Ok(OutputProfile { data, profile })
+    }
+}
+
+/// sRGB profile, used when there is no output profile.
+fn srgb() -> &'static Profile {
+    static SRGB: OnceLock<Box<Profile>> = OnceLock::new();
+    SRGB.get_or_init(|| {
+        let mut profile = Profile::new_sRGB();
+        profile.precache_output_transform();
+        profile
+    })
+}
+
+/// Convert the colors of `image` from its profile to the output profile.
+///
+/// Images without a profile are assumed to be in sRGB. Images with 16-bit
+/// or floating-point samples are tonemapped to 8-bit samples before the
+/// conversion, since the transforms only support 8-bit samples.
+pub fn convert(image: DynamicImage, input: Option<&Profile>, options: &Options) -> DynamicImage {
+    let input = input.unwrap_or_else(|| srgb());
+    let output = match options.output_profile {
+        Some(output) => &output.profile,
+        None => srgb(),
+    };
+
+    if input.is_sRGB() && output.is_sRGB() {
+        return image;
+    }
+
+    let image = match tonemap::apply(image, &options.tonemap) {
+        image @ DynamicImage::ImageLuma8(_) => DynamicImage::ImageRgb8(image.into_rgb8()),
+        image @ DynamicImage::ImageLumaA8(_) => DynamicImage::ImageRgba8(image.into_rgba8()),
+        image => image,
+    };
+
+    match image {
+        DynamicImage::ImageRgb8(mut pixels) => {
+            if let Some(transform) =
+                Transform::new(input, output, DataType::RGB8, Intent::Perceptual)
+            {
+                transform.apply(&mut pixels);
+            }
+
+            DynamicImage::ImageRgb8(pixels)
+        }
+
+        DynamicImage::ImageRgba8(mut pixels) => {
+            if let Some(transform) =
+                Transform::new(input, output, DataType::RGBA8, Intent::Perceptual)
+            {
+                transform.apply(&mut pixels);
+            }
+
+            DynamicImage::ImageRgba8(pixels)
+        }
+
+        image => image,
+    }
+}
+
+/// Returns `true` if the ICC profile is for the RGB color space.
+fn is_rgb(icc: &[u8]) -> bool {
+    icc.get(COLOR_SPACE_RANGE) == Some(b"RGB ")
+}
+
+/// Parse an ICC profile. Only RGB profiles can be applied to the decoded
+/// pixels, so other profiles are ignored.
+pub fn parse(icc: &[u8]) -> Option<Box<Profile>> {
+    if !is_rgb(icc) {
+        return None;
+    }
+
+    Profile::new_from_slice(icc, false)
+}
+
+/// Profile for the color primaries and the transfer characteristics defined
+/// in ITU-T H.273, like the ones in the `nclx` boxes of HEIF images.
+#[cfg(feature = "heif")]
+pub fn from_cicp(primaries: u8, transfer: u8) -> Option<Box<Profile>> {
+    // qcms panics with reserved or unspecified values.
+    if !matches!(primaries, 1 | 4..=12 | 22) || !matches!(transfer, 1 | 4..=18) {
+        return None;
+    }
+
+    Profile::new_cicp(primaries.into(), transfer.into())
+}
+
+/// ICC profile embedded in an image.
+///
+/// For JPEG files, the profile is read from the APP2 segments, since they
+/// are decoded with turbojpeg. For other formats, it uses the decoders of
+/// the `image` crate.
+pub fn embedded_profile(data: &[u8]) -> Option<Box<Profile>> {
+    let icc = if data.starts_with(&[0xFF, 0xD8]) {
+        jpeg_profile(data)?
+    } else {
+        ImageReader::new(Cursor::new(data))
+            .with_guessed_format()
+            .ok()?
+            .into_decoder()
+            .ok()?
+            .icc_profile()
+            .ok()??
+    };
+
+    parse(&icc)
+}
+
+/// Join the chunks of the ICC profile stored in the APP2 segments of a JPEG
+/// file.
+///
+/// Every chunk starts with the identifier, followed by its sequence number
+/// (starting at 1), and the number of chunks.
+fn jpeg_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks: Vec<_> = jpeg::segments(data)
+        .filter(|s| s.marker == MARKER_APP2)
+        .filter_map(|s| match s.contents.strip_prefix(ICC_IDENTIFIER)? {
+            [seq_no, _, chunk @ ..] => Some((*seq_no, chunk)),
+            _ => None,
+        })
+        .collect();
+
+    if chunks.is_empty() {
+        return None;
+    }
+
+    chunks.sort_by_key(|(seq_no, _)| *seq_no);
+    Some(
+        chunks
+            .into_iter()
+            .flat_map(|(_, chunk)| chunk)
+            .copied()
+            .collect(),
+    )
+}
@@ -0,0 +1,56 @@
+//! Read the metadata segments of JPEG files.
+
+/// Marker for the APP2 segment, where the ICC profile and the MPF data are
+/// stored.
+pub const MARKER_APP2: u8 = 0xE2;
+
+/// Marker for the Start Of Scan. Metadata segments are before it.
+const MARKER_SOS: u8 = 0xDA;
+
+/// Start Of Image, at the beginning of every JPEG file.
+const SOI: &[u8] = &[0xFF, 0xD8];
+
+pub struct Segment<'a> {
+    pub marker: u8,
+
+    /// Position of the contents in the file.
+    pub offset: usize,
+
+    /// Contents of the segment, without the marker and the length.
+    pub contents: &'a [u8],
+}
+
+/// Iterate over the segments before the Start Of Scan.
+///
+/// The iteration stops at the first segment that is incomplete, or if `data`
+/// is not a JPEG file.
+pub fn segments(data: &[u8]) -> impl Iterator<Item = Segment<'_>> {
+    let mut pos = if data.starts_with(SOI) {
+        SOI.len()
+    } else {
+        data.len()
+    };
+
+    std::iter::from_fn(move || {
+        let &[0xFF, marker, hi, lo] = data.get(pos..pos + 4)? else {
+            return None;
+        };
+
+        if marker == MARKER_SOS {
+            return None;
+        }
+
+        // The length includes its own two bytes.
+        let length = usize::from(u16::from_be_bytes([hi, lo]));
+        let contents = data.get(pos + 4..pos + 2 + length)?;
+
+        let segment = Segment {
+            marker,
+            offset: pos + 4,
+            contents,
+        };
+
+        pos += 2 + length;
+        Some(segment)
+    })
+}
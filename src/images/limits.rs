@@ -0,0 +1,90 @@
+//! Limits for the size of the decoded images.
+//!
+//! The memory for the pixels of an image is reserved before decoding it, from
+//! a budget shared by all workers. If there is not enough memory available,
+//! the worker waits until other images are released.
+
+use std::sync::{Condvar, Mutex};
+
+use bytesize::ByteSize;
+
+pub struct Limits {
+    /// Maximum number of pixels in an image.
+    max_pixels: Option<u64>,
+
+    budget: Budget,
+}
+
+struct Budget {
+    total: u64,
+    available: Mutex<u64>,
+    released: Condvar,
+}
+
+/// Memory reserved for an image. It is returned to the budget when dropped.
+pub struct Reservation<'a> {
+    budget: &'a Budget,
+    bytes: u64,
+}
+
+impl Limits {
+    pub fn new(max_pixels: Option<u64>, max_memory: u64) -> Limits {
+        Limits {
+            max_pixels,
+            budget: Budget {
+                total: max_memory,
+                available: Mutex::new(max_memory),
+                released: Condvar::new(),
+            },
+        }
+    }
+
+    /// Limits for the decoders of the `image` crate.
+    pub fn decoder_limits(&self) -> image::Limits {
+        let mut limits = image::Limits::default();
+        limits.max_alloc = Some(self.budget.total);
+        limits
+    }
+
+    /// Check the dimensions of an image, and reserve `bytes` for its pixels.
+    ///
+    /// Returns an error if the image exceeds the limits, even if all the
+    /// budget were available.
+    pub fn reserve(&self, width: u32, height: u32, bytes: u64) -> anyhow::Result<Reservation<'_>> {
+        let pixels = u64::from(width) * u64::from(height);
+        if let Some(max_pixels) = self.max_pixels {
+            if pixels > max_pixels {
+                anyhow::bail!("Image exceeds the maximum number of pixels ({width}x{height})");
+            }
+        }
+
+        if bytes > self.budget.total {
+            anyhow::bail!(
+                "Image exceeds the maximum memory ({} > {})",
+                ByteSize(bytes),
+                ByteSize(self.budget.total)
+            );
+        }
+
+        let available = self.budget.available.lock().unwrap();
+        let mut available = self
+            .budget
+            .released
+            .wait_while(available, |available| *available < bytes)
+            .unwrap();
+
+        *available -= bytes;
+
+        Ok(Reservation {
+            budget: &self.budget,
+            bytes,
+        })
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        *self.budget.available.lock().unwrap() += self.bytes;
+        self.budget.released.notify_all();
+    }
+}
@@ -0,0 +1,17 @@
+//! Read metadata embedded in image files.
+
+use std::io::Cursor;
+
+use image::metadata::Orientation;
+
+/// Read the orientation from the EXIF data in the image container (JPEG,
+/// TIFF, HEIF, PNG, or WebP).
+pub fn orientation(data: &[u8]) -> Option<Orientation> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()?;
+
+    let field = exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?;
+    let value = field.value.get_uint(0)?;
+    Orientation::from_exif(u8::try_from(value).ok()?)
+}
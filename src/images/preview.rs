@@ -0,0 +1,180 @@
+//! Extract previews embedded in JPEG files.
+//!
+//! Cameras usually store small versions of the photo, either as the EXIF
+//! thumbnail (in IFD1), or as secondary images in the Multi-Picture Format
+//! (MPF) segment.
+
+use std::fs::File;
+use std::io::Cursor;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+
+use super::jpeg::{self, MARKER_APP2};
+use super::tiff::Tiff;
+use super::Options;
+
+/// Identifier of the MPF segment.
+const MPF_IDENTIFIER: &[u8] = b"MPF\0";
+
+/// Tag in the MPF index IFD with the list of images.
+const TAG_MP_ENTRY: u16 = 0xB002;
+
+/// Size of every item in the `MPEntry` field.
+const MP_ENTRY_SIZE: usize = 16;
+
+/// Find the smallest preview in a JPEG file that is large enough to generate
+/// the thumbnail. The preview is returned as a JPEG image.
+pub fn find(data: &[u8], options: &Options) -> Option<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok();
+
+    let mut previews: Vec<&[u8]> = exif.as_ref().and_then(exif_thumbnail).into_iter().collect();
+    previews.extend(mpf_images(data));
+
+    select(previews, options, false).map(<[u8]>::to_vec)
+}
+
+/// Find a preview in a JPEG file that is too large to be read.
+///
+/// `head` contains the first bytes of the file, with the EXIF and MPF
+/// segments. The MPF images are stored after the primary image, so they are
+/// read from `file`. Images larger than `max_size` are ignored.
+pub fn find_in_file(file: &File, head: &[u8], max_size: u64, options: &Options) -> Option<Vec<u8>> {
+    if !head.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(head))
+        .ok();
+
+    let mut previews: Vec<Vec<u8>> = exif
+        .as_ref()
+        .and_then(exif_thumbnail)
+        .map(<[u8]>::to_vec)
+        .into_iter()
+        .collect();
+
+    if let Some(mpf_offset) = find_mpf_segment(head) {
+        for range in mpf_entries(&head[mpf_offset..]).unwrap_or_default() {
+            if range.len() as u64 > max_size {
+                continue;
+            }
+
+            let mut preview = vec![0; range.len()];
+            if file
+                .read_exact_at(&mut preview, (mpf_offset + range.start) as u64)
+                .is_ok()
+            {
+                previews.push(preview);
+            }
+        }
+    }
+
+    select(previews, options, false)
+}
+
+/// Choose the smallest JPEG preview that is large enough to generate the
+/// thumbnail.
+///
+/// If no preview is large enough and `allow_smaller` is `true`, the largest
+/// one is chosen.
+pub fn select<T: AsRef<[u8]>>(
+    previews: impl IntoIterator<Item = T>,
+    options: &Options,
+    allow_smaller: bool,
+) -> Option<T> {
+    let mut previews: Vec<_> = previews
+        .into_iter()
+        .filter_map(|preview| {
+            let header = turbojpeg::read_header(preview.as_ref()).ok()?;
+            let (width, height) = (header.width as u32, header.height as u32);
+            let large_enough = options.is_large_enough(width, height);
+            Some((u64::from(width) * u64::from(height), large_enough, preview))
+        })
+        .collect();
+
+    previews.sort_by_key(|(pixels, _, _)| *pixels);
+
+    if let Some(index) = previews
+        .iter()
+        .position(|(_, large_enough, _)| *large_enough)
+    {
+        return Some(previews.swap_remove(index).2);
+    }
+
+    if allow_smaller {
+        previews.pop().map(|(_, _, preview)| preview)
+    } else {
+        None
+    }
+}
+
+/// JPEG image stored as the EXIF thumbnail.
+fn exif_thumbnail(exif: &exif::Exif) -> Option<&[u8]> {
+    let field = |tag| {
+        let field = exif.get_field(tag, exif::In::THUMBNAIL)?;
+        field.value.get_uint(0).map(|v| v as usize)
+    };
+
+    let offset = field(exif::Tag::JPEGInterchangeFormat)?;
+    let length = field(exif::Tag::JPEGInterchangeFormatLength)?;
+    exif.buf().get(offset..offset.checked_add(length)?)
+}
+
+/// Secondary images in the MPF segment.
+fn mpf_images(data: &[u8]) -> Vec<&[u8]> {
+    let Some(offset) = find_mpf_segment(data) else {
+        return Vec::new();
+    };
+
+    let mpf = &data[offset..];
+    mpf_entries(mpf)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|range| mpf.get(range))
+        .collect()
+}
+
+/// Returns the offset of the TIFF header in the MPF segment.
+fn find_mpf_segment(data: &[u8]) -> Option<usize> {
+    jpeg::segments(data)
+        .find(|s| s.marker == MARKER_APP2 && s.contents.starts_with(MPF_IDENTIFIER))
+        .map(|s| s.offset + MPF_IDENTIFIER.len())
+}
+
+/// Parse the `MPEntry` field. `mpf` starts at the TIFF header of the MPF
+/// segment, which is also the base for the offsets of the images.
+///
+/// Returns the position of the secondary images, relative to `mpf`. They
+/// may be beyond the end of `mpf`.
+fn mpf_entries(mpf: &[u8]) -> Option<Vec<Range<usize>>> {
+    let tiff = Tiff::new(mpf)?;
+    let (entries, _) = tiff.ifd(tiff.first_ifd()?)?;
+    let entry = entries.iter().find(|e| e.tag == TAG_MP_ENTRY)?;
+
+    let base = entry.value as usize;
+    let count = entry.count as usize / MP_ENTRY_SIZE;
+
+    let images = (0..count)
+        .filter_map(|i| {
+            let pos = base + i * MP_ENTRY_SIZE;
+            let size = tiff.u32(pos + 4)? as usize;
+            let offset = tiff.u32(pos + 8)? as usize;
+
+            // The offset of the primary image is 0.
+            if offset == 0 {
+                return None;
+            }
+
+            Some(offset..offset.checked_add(size)?)
+        })
+        .collect();
+
+    Some(images)
+}
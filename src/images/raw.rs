@@ -0,0 +1,197 @@
+//! Extract the JPEG previews embedded in camera RAW files.
+//!
+//! Most RAW formats are based on TIFF, and the previews are stored in some of
+//! their IFDs. Fujifilm RAF files use their own header, with the offset of a
+//! JPEG image.
+
+use std::ops::Range;
+use std::path::Path;
+
+use image::metadata::Orientation;
+
+use super::tiff::{Bytes, Tiff};
+use super::{limits, preview, Loaded, Options};
+
+/// Extensions of the supported RAW formats.
+const RAW_EXTENSIONS: &[&str] = &[
+    "3fr", "arw", "cr2", "dng", "erf", "iiq", "kdc", "mos", "nef", "nrw", "orf", "pef", "raf",
+    "rw2", "rwl", "sr2", "srf", "srw",
+];
+
+/// Header of RAF files, and position of the offset and length of the JPEG
+/// image.
+const RAF_MAGIC: &[u8] = b"FUJIFILMCCD-RAW ";
+const RAF_JPEG_OFFSET: usize = 84;
+
+/// TIFF tags to locate the previews.
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_PHOTOMETRIC: u16 = 0x0106;
+const TAG_STRIP_OFFSETS: u16 = 0x0111;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x0117;
+const TAG_SUB_IFDS: u16 = 0x014A;
+const TAG_JPEG_OFFSET: u16 = 0x0201;
+const TAG_JPEG_LENGTH: u16 = 0x0202;
+
+/// Values of the `Compression` tag for JPEG data.
+const COMPRESSION_OLD_JPEG: u32 = 6;
+const COMPRESSION_JPEG: u32 = 7;
+
+/// Values of the `PhotometricInterpretation` tag for the raw data in DNG
+/// files, which can be stored as lossless JPEG.
+const PHOTOMETRIC_CFA: u32 = 32803;
+const PHOTOMETRIC_LINEAR_RAW: u32 = 34892;
+
+/// Limit for the number of IFDs, to avoid loops in broken files.
+const MAX_IFDS: usize = 64;
+
+/// Bytes to read from every preview to get its dimensions. The header must
+/// be complete until the Start Of Scan, which is after the EXIF data
+/// (limited to 64K).
+const PREVIEW_HEAD_SIZE: usize = 128 << 10;
+
+/// JPEG image found in a RAW file. Only the first bytes are read until it is
+/// selected.
+struct Preview {
+    range: Range<usize>,
+    head: Vec<u8>,
+}
+
+impl AsRef<[u8]> for Preview {
+    fn as_ref(&self) -> &[u8] {
+        &self.head
+    }
+}
+
+pub fn has_raw_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| RAW_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Load the preview of a RAW file.
+///
+/// It uses the smallest preview that is large enough for the thumbnail, or
+/// the largest one if all of them are smaller. Previews larger than
+/// `max_size` are ignored.
+pub fn load<'l, B: Bytes + ?Sized>(
+    data: &B,
+    options: &Options,
+    max_size: u64,
+    limits: &'l limits::Limits,
+) -> anyhow::Result<Loaded<'l>> {
+    let mut magic = [0; RAF_MAGIC.len()];
+    data.read_at(0, &mut magic)
+        .ok_or_else(|| anyhow::anyhow!("RAW file is too short"))?;
+
+    let (ranges, orientation) = if magic == RAF_MAGIC {
+        raf_preview(data)
+    } else {
+        tiff_previews(data)
+    };
+
+    let previews = ranges
+        .into_iter()
+        .filter(|range| range.len() as u64 <= max_size)
+        .filter_map(|range| read_head(data, range));
+
+    let Preview { range, .. } = preview::select(previews, options, true)
+        .ok_or_else(|| anyhow::anyhow!("No preview found in RAW file"))?;
+
+    let mut preview = vec![0; range.len()];
+    data.read_at(range.start, &mut preview)
+        .ok_or_else(|| anyhow::anyhow!("Cannot read the preview of RAW file"))?;
+
+    // The previews in RAF files have their own EXIF data.
+    let orientation = orientation.or_else(|| super::metadata::orientation(&preview));
+
+    let loaded = super::decode_pixels(&preview, options, limits)?;
+    Ok(loaded.map(|image| super::orient(image, orientation, options)))
+}
+
+fn raf_preview<B: Bytes + ?Sized>(data: &B) -> (Vec<Range<usize>>, Option<Orientation>) {
+    let mut header = [0; 8];
+    let preview = data.read_at(RAF_JPEG_OFFSET, &mut header).and_then(|_| {
+        let offset = u32::from_be_bytes(header[0..4].try_into().ok()?) as usize;
+        let length = u32::from_be_bytes(header[4..8].try_into().ok()?) as usize;
+        Some(offset..offset.checked_add(length)?)
+    });
+
+    (preview.into_iter().collect(), None)
+}
+
+/// Collect the position of the JPEG images in all IFDs, and the orientation
+/// from the first one.
+fn tiff_previews<B: Bytes + ?Sized>(data: &B) -> (Vec<Range<usize>>, Option<Orientation>) {
+    let mut previews = Vec::new();
+    let mut orientation = None;
+
+    let Some(tiff) = Tiff::new(data) else {
+        return (previews, orientation);
+    };
+
+    let mut pending: Vec<usize> = tiff.first_ifd().into_iter().collect();
+    let mut visited = 0;
+
+    while let Some(offset) = pending.pop() {
+        if offset == 0 || visited >= MAX_IFDS {
+            continue;
+        }
+
+        visited += 1;
+
+        let Some((entries, next)) = tiff.ifd(offset) else {
+            continue;
+        };
+
+        pending.push(next);
+
+        let field = |tag| entries.iter().find(|e| e.tag == tag);
+        let value = |tag| field(tag).map(|e| e.value as usize);
+
+        if visited == 1 {
+            orientation = value(TAG_ORIENTATION)
+                .and_then(|o| u8::try_from(o).ok())
+                .and_then(Orientation::from_exif);
+        }
+
+        if let Some(sub_ifds) = field(TAG_SUB_IFDS).and_then(|e| tiff.values(e)) {
+            pending.extend(sub_ifds.into_iter().map(|o| o as usize));
+        }
+
+        if let (Some(offset), Some(length)) = (value(TAG_JPEG_OFFSET), value(TAG_JPEG_LENGTH)) {
+            previews.extend(offset.checked_add(length).map(|end| offset..end));
+        }
+
+        // Some formats store the preview as a single strip with JPEG data.
+        // DNG files can store the raw data in the same way.
+        let compression = value(TAG_COMPRESSION).map(|c| c as u32);
+        let photometric = value(TAG_PHOTOMETRIC).map(|p| p as u32);
+        if matches!(compression, Some(COMPRESSION_OLD_JPEG | COMPRESSION_JPEG))
+            && !matches!(photometric, Some(PHOTOMETRIC_CFA | PHOTOMETRIC_LINEAR_RAW))
+        {
+            let offsets = field(TAG_STRIP_OFFSETS).and_then(|e| tiff.values(e));
+            let lengths = field(TAG_STRIP_BYTE_COUNTS).and_then(|e| tiff.values(e));
+
+            if let (Some(&[offset]), Some(&[length])) = (offsets.as_deref(), lengths.as_deref()) {
+                let (offset, length) = (offset as usize, length as usize);
+                previews.extend(offset.checked_add(length).map(|end| offset..end));
+            }
+        }
+    }
+
+    (previews, orientation)
+}
+
+/// Read the first bytes of a preview, if it is a JPEG image.
+fn read_head<B: Bytes + ?Sized>(data: &B, range: Range<usize>) -> Option<Preview> {
+    let mut head = vec![0; range.len().min(PREVIEW_HEAD_SIZE)];
+    data.read_at(range.start, &mut head)?;
+
+    if !head.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    Some(Preview { range, head })
+}
@@ -0,0 +1,57 @@
+//! Rasterize SVG images with resvg.
+
+use std::sync::{Arc, OnceLock};
+
+use anyhow::Context;
+use image::{DynamicImage, RgbaImage};
+use resvg::tiny_skia::{Pixmap, Transform};
+use resvg::usvg::{self, fontdb};
+
+use super::Options;
+
+/// Render the SVG document to fit in the thumbnail.
+///
+/// The image is rasterized directly to the size of the thumbnail, instead of
+/// resizing a bitmap.
+pub fn decode(data: &[u8], options: &Options) -> anyhow::Result<DynamicImage> {
+    let usvg_options = usvg::Options {
+        fontdb: Arc::clone(fonts()),
+        ..usvg::Options::default()
+    };
+
+    let tree = usvg::Tree::from_data(data, &usvg_options)?;
+    let size = tree.size();
+
+    let (width, height) = options.fit(size.width(), size.height());
+
+    let mut pixmap = Pixmap::new(width, height).context("Invalid SVG size")?;
+    resvg::render(
+        &tree,
+        Transform::from_scale(width as f32 / size.width(), height as f32 / size.height()),
+        &mut pixmap.as_mut(),
+    );
+
+    // Pixels in the pixmap use premultiplied alpha.
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+
+    let image = RgbaImage::from_raw(width, height, pixels).context("Invalid SVG size")?;
+    Ok(DynamicImage::ImageRgba8(image))
+}
+
+/// Fonts available in the system, loaded when the first SVG is rendered.
+fn fonts() -> &'static Arc<fontdb::Database> {
+    static FONTS: OnceLock<Arc<fontdb::Database>> = OnceLock::new();
+
+    FONTS.get_or_init(|| {
+        let mut fonts = fontdb::Database::new();
+        fonts.load_system_fonts();
+        Arc::new(fonts)
+    })
+}
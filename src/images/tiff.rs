@@ -0,0 +1,156 @@
+//! Minimal reader for TIFF structures, to find images embedded in other
+//! files.
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+/// Field types for 16-bit and 32-bit unsigned integers, and for offsets to
+/// IFDs.
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_IFD: u16 = 13;
+
+/// Source of the TIFF data.
+///
+/// It can be read from a file, so the data doesn't have to be loaded in
+/// memory.
+pub trait Bytes {
+    /// Fill `buf` with the bytes at `offset`.
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Option<()>;
+}
+
+impl Bytes for [u8] {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Option<()> {
+        buf.copy_from_slice(self.get(offset..offset.checked_add(buf.len())?)?);
+        Some(())
+    }
+}
+
+impl Bytes for File {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Option<()> {
+        self.read_exact_at(buf, offset as u64).ok()
+    }
+}
+
+pub struct Tiff<'a, B: Bytes + ?Sized> {
+    data: &'a B,
+    little_endian: bool,
+}
+
+/// Entry in an IFD.
+pub struct Entry {
+    pub tag: u16,
+    pub kind: u16,
+    pub count: u32,
+
+    /// Value of the field if it fits in 4 bytes. Otherwise, offset to the
+    /// value.
+    pub value: u32,
+}
+
+impl<'a, B: Bytes + ?Sized> Tiff<'a, B> {
+    /// Parse the TIFF header. Some variants used by camera RAW files (ORF,
+    /// RW2) are also accepted.
+    pub fn new(data: &'a B) -> Option<Self> {
+        let mut magic = [0; 4];
+        data.read_at(0, &mut magic)?;
+
+        let little_endian = match &magic {
+            b"II*\0" | b"IIRO" | b"IIRS" | b"IIU\0" => true,
+            b"MM\0*" | b"MMOR" => false,
+            _ => return None,
+        };
+
+        Some(Tiff {
+            data,
+            little_endian,
+        })
+    }
+
+    /// Offset of the first IFD.
+    pub fn first_ifd(&self) -> Option<usize> {
+        self.u32(4).map(|offset| offset as usize)
+    }
+
+    /// Read the entries of the IFD at `offset`.
+    ///
+    /// Returns the entries and the offset of the next IFD, which is `0` if
+    /// this is the last one.
+    pub fn ifd(&self, offset: usize) -> Option<(Vec<Entry>, usize)> {
+        let count = usize::from(self.u16(offset)?);
+
+        let entries = (0..count)
+            .map(|i| {
+                let pos = offset + 2 + i * 12;
+                let kind = self.u16(pos + 2)?;
+                let count = self.u32(pos + 4)?;
+
+                // Short values are stored in the first bytes of the field.
+                let value = if kind == TYPE_SHORT && count == 1 {
+                    u32::from(self.u16(pos + 8)?)
+                } else {
+                    self.u32(pos + 8)?
+                };
+
+                Some(Entry {
+                    tag: self.u16(pos)?,
+                    kind,
+                    count,
+                    value,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        let next = self.u32(offset + 2 + count * 12)? as usize;
+        Some((entries, next))
+    }
+
+    /// Read all values of a field with integers.
+    pub fn values(&self, entry: &Entry) -> Option<Vec<u32>> {
+        let count = entry.count as usize;
+
+        match entry.kind {
+            _ if count == 1 => Some(vec![entry.value]),
+
+            // Two short values are stored in the field.
+            TYPE_SHORT if count == 2 => {
+                let (first, second) = (entry.value >> 16, entry.value & 0xFFFF);
+                if self.little_endian {
+                    Some(vec![second, first])
+                } else {
+                    Some(vec![first, second])
+                }
+            }
+
+            TYPE_SHORT => (0..count)
+                .map(|i| self.u16(entry.value as usize + i * 2).map(u32::from))
+                .collect(),
+
+            TYPE_LONG | TYPE_IFD => (0..count)
+                .map(|i| self.u32(entry.value as usize + i * 4))
+                .collect(),
+
+            _ => None,
+        }
+    }
+
+    pub fn u16(&self, offset: usize) -> Option<u16> {
+        let mut bytes = [0; 2];
+        self.data.read_at(offset, &mut bytes)?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    pub fn u32(&self, offset: usize) -> Option<u32> {
+        let mut bytes = [0; 4];
+        self.data.read_at(offset, &mut bytes)?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+}
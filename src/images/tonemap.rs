@@ -0,0 +1,181 @@
+//! Tonemapping for images with 16-bit or floating-point samples.
+//!
+//! Floating-point images (like OpenEXR or Radiance HDR) are assumed to be
+//! in linear light, with values above 1.0 for highlights. 16-bit images are
+//! assumed to be sRGB-encoded, like 8-bit images.
+
+use image::{DynamicImage, Rgb32FImage, RgbImage, Rgba32FImage, RgbaImage};
+
+/// Luminance of the mid-gray, used as the target for the auto-exposure.
+const MID_GRAY: f32 = 0.18;
+
+/// Range, in EV, of the histogram for the auto-exposure.
+const HISTOGRAM_MIN_EV: f32 = -16.0;
+const HISTOGRAM_MAX_EV: f32 = 16.0;
+
+/// Bins in the histogram for every EV.
+const HISTOGRAM_BINS_PER_EV: f32 = 8.0;
+
+/// Operator to map the luminance of the image to the range of the display.
+#[derive(clap::ValueEnum, Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum Operator {
+    /// Clip values above the white.
+    Clip,
+
+    /// Extended Reinhard operator, with the brightest pixel as the white.
+    #[default]
+    Reinhard,
+
+    /// Approximation of the ACES filmic curve.
+    Aces,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Tonemap {
+    pub operator: Operator,
+
+    /// Exposure compensation, in EV.
+    pub exposure: f32,
+
+    /// Compute the exposure from the histogram of the image, so its median
+    /// luminance is mapped to the mid-gray.
+    pub auto_exposure: bool,
+}
+
+/// Convert 16-bit or floating-point images to 8-bit samples.
+///
+/// Images with 8-bit samples are returned without changes.
+pub fn apply(image: DynamicImage, tonemap: &Tonemap) -> DynamicImage {
+    match image {
+        DynamicImage::ImageRgb32F(image) => {
+            let (width, height) = image.dimensions();
+            let pixels = map(image.into_raw(), 3, false, tonemap);
+            DynamicImage::ImageRgb8(RgbImage::from_raw(width, height, pixels).unwrap())
+        }
+
+        DynamicImage::ImageRgba32F(image) => rgba(image, false, tonemap),
+
+        DynamicImage::ImageLuma16(_) | DynamicImage::ImageRgb16(_) => {
+            let image: Rgb32FImage = image.into_rgb32f();
+            let (width, height) = image.dimensions();
+            let pixels = map(image.into_raw(), 3, true, tonemap);
+            DynamicImage::ImageRgb8(RgbImage::from_raw(width, height, pixels).unwrap())
+        }
+
+        DynamicImage::ImageLumaA16(_) | DynamicImage::ImageRgba16(_) => {
+            rgba(image.into_rgba32f(), true, tonemap)
+        }
+
+        image => image,
+    }
+}
+
+fn rgba(image: Rgba32FImage, srgb_encoded: bool, tonemap: &Tonemap) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    let pixels = map(image.into_raw(), 4, srgb_encoded, tonemap);
+    DynamicImage::ImageRgba8(RgbaImage::from_raw(width, height, pixels).unwrap())
+}
+
+/// Map the samples of an image, with `channels` samples per pixel, to 8-bit
+/// sRGB values. If there is a fourth channel, it is the alpha.
+fn map(mut samples: Vec<f32>, channels: usize, srgb_encoded: bool, tonemap: &Tonemap) -> Vec<u8> {
+    if srgb_encoded {
+        for pixel in samples.chunks_exact_mut(channels) {
+            pixel[..3].iter_mut().for_each(|s| *s = srgb_to_linear(*s));
+        }
+    }
+
+    let mut scale = tonemap.exposure.exp2();
+    if tonemap.auto_exposure {
+        if let Some(median) = median_luminance(&samples, channels) {
+            scale *= MID_GRAY / median;
+        }
+    }
+
+    // White point for the Reinhard operator.
+    let white = samples
+        .chunks_exact(channels)
+        .map(|pixel| luminance(pixel) * scale)
+        .fold(1.0, f32::max);
+
+    let mut output = Vec::with_capacity(samples.len());
+    for pixel in samples.chunks_exact(channels) {
+        let rgb = [pixel[0], pixel[1], pixel[2]].map(|s| s * scale);
+
+        let rgb = match tonemap.operator {
+            Operator::Clip => rgb,
+
+            Operator::Reinhard => {
+                let l = luminance(&rgb);
+                if l > 0.0 {
+                    let mapped = l * (1.0 + l / (white * white)) / (1.0 + l);
+                    rgb.map(|s| s * mapped / l)
+                } else {
+                    rgb
+                }
+            }
+
+            Operator::Aces => rgb.map(|s| (s * (2.51 * s + 0.03)) / (s * (2.43 * s + 0.59) + 0.14)),
+        };
+
+        output.extend(rgb.map(|s| to_u8(linear_to_srgb(s))));
+        output.extend(pixel.get(3).map(|&a| to_u8(a)));
+    }
+
+    output
+}
+
+/// Median of the luminance of the pixels, computed from a histogram in
+/// logarithmic scale. Black pixels are ignored.
+fn median_luminance(samples: &[f32], channels: usize) -> Option<f32> {
+    let bins = ((HISTOGRAM_MAX_EV - HISTOGRAM_MIN_EV) * HISTOGRAM_BINS_PER_EV) as usize;
+    let mut histogram = vec![0_usize; bins];
+
+    for pixel in samples.chunks_exact(channels) {
+        let l = luminance(pixel);
+        if l > 0.0 && l.is_finite() {
+            let bin = (l.log2() - HISTOGRAM_MIN_EV) * HISTOGRAM_BINS_PER_EV;
+            histogram[(bin.max(0.0) as usize).min(bins - 1)] += 1;
+        }
+    }
+
+    let total: usize = histogram.iter().sum();
+    if total == 0 {
+        return None;
+    }
+
+    let mut count = 0;
+    let bin = histogram.iter().position(|&n| {
+        count += n;
+        count * 2 >= total
+    })?;
+
+    let ev = HISTOGRAM_MIN_EV + (bin as f32 + 0.5) / HISTOGRAM_BINS_PER_EV;
+    Some(ev.exp2())
+}
+
+/// Relative luminance of a linear RGB pixel (Rec. 709 primaries).
+fn luminance(pixel: &[f32]) -> f32 {
+    0.2126 * pixel[0] + 0.7152 * pixel[1] + 0.0722 * pixel[2]
+}
+
+fn srgb_to_linear(s: f32) -> f32 {
+    if s <= 0.04045 {
+        s / 12.92
+    } else {
+        ((s + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(s: f32) -> f32 {
+    if s <= 0.0031308 {
+        s * 12.92
+    } else {
+        1.055 * s.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn to_u8(s: f32) -> u8 {
+    // NaN values are converted to 0.
+    (s.clamp(0.0, 1.0) * 255.0).round() as u8
+}
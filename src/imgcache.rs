@@ -1,27 +1,67 @@
 //! Keep a cache of generated thumbnails.
 
+pub mod freedesktop;
+mod packed;
+
 use std::env;
-use std::fs::OpenOptions;
 use std::io::Write;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use image::DynamicImage;
 use sha2::{Digest, Sha224};
 
-use crate::images::Thumbnail;
+use crate::images::{Background, Options, Thumbnail};
+use freedesktop::SharedThumbnails;
+use packed::PackedStore;
 
 /// Variable name to use a specific cache for this program.
 const CACHE_DIR_ENV: &str = "LIST_IMAGES_CACHE";
 
+/// End Of Image marker, expected at the end of every JPEG file.
+const JPEG_EOI: &[u8] = &[0xFF, 0xD9];
+
+/// Signature at the beginning of every PNG file.
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1A\n";
+
+/// `IEND` chunk, expected at the end of every PNG file.
+const PNG_IEND: &[u8] = b"\0\0\0\0IEND\xAE\x42\x60\x82";
+
+/// File name of the database for the packed store.
+const PACKED_STORE_FILE: &str = "thumbnails.redb";
+
+#[derive(Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum StoreKind {
+    /// One file for every thumbnail.
+    Files,
+
+    /// A single database file for all thumbnails.
+    Packed,
+}
+
+enum Store {
+    Files(PathBuf),
+    Packed(PackedStore),
+}
+
 pub struct Cache {
-    thumbnail_size: u32,
+    options: Options,
 
-    cache_dir: PathBuf,
+    /// Shared by the caches for different options, since the packed
+    /// database can be opened only once.
+    store: Arc<Store>,
+
+    shared: Option<SharedThumbnails>,
 }
 
 impl Cache {
-    pub fn new(thumbnail_size: u32) -> Option<Cache> {
+    pub fn new(
+        options: Options,
+        store_kind: StoreKind,
+        shared: Option<SharedThumbnails>,
+    ) -> Option<Cache> {
         let cache_dir = if let Some(value) = env::var_os(CACHE_DIR_ENV) {
             PathBuf::from(value)
         } else {
@@ -29,21 +69,71 @@ impl Cache {
             path.join(env!("CARGO_PKG_NAME"))
         };
 
+        let store = match store_kind {
+            StoreKind::Files => Store::Files(cache_dir),
+            StoreKind::Packed => match PackedStore::open(&cache_dir.join(PACKED_STORE_FILE)) {
+                Some(packed) => Store::Packed(packed),
+
+                // The database is locked by another process. Use one file
+                // for every thumbnail, so the cache (and the shared
+                // thumbnails) are still available.
+                None => Store::Files(cache_dir),
+            },
+        };
+
         Some(Cache {
-            thumbnail_size,
-            cache_dir,
+            options,
+            store: Arc::new(store),
+            shared,
         })
     }
 
+    /// Create a cache for thumbnails generated with other options, using
+    /// the same store.
+    pub fn with_options(&self, options: Options, shared: Option<SharedThumbnails>) -> Cache {
+        Cache {
+            options,
+            store: Arc::clone(&self.store),
+            shared,
+        }
+    }
+
+    /// Get a thumbnail from the cache. If it is not available, try with the
+    /// shared thumbnails.
     pub fn get(&self, path: &Path) -> Option<Thumbnail> {
-        let cached_path = self.file_hash(path)?;
-        let data = std::fs::read(cached_path).ok()?;
+        if let Some(thumbnail) = self.get_cached(path) {
+            return Some(thumbnail);
+        }
+
+        let thumbnail = self.shared.as_ref()?.get(path)?;
+        self.store(path, &thumbnail);
+        Some(thumbnail)
+    }
+
+    fn get_cached(&self, path: &Path) -> Option<Thumbnail> {
+        let key = self.key(path);
+        let data = match &*self.store {
+            Store::Files(cache_dir) => std::fs::read(cached_path(cache_dir, &key)).ok()?,
+            Store::Packed(packed) => packed.get(&key)?,
+        };
 
-        let header = turbojpeg::read_header(&data).ok()?;
+        // Discard entries that are not a complete image, like the ones left
+        // by an older version of this program when it was killed while
+        // writing the file.
+        let dimensions = if data.starts_with(PNG_SIGNATURE) {
+            png_dimensions(&data)
+        } else {
+            jpeg_dimensions(&data)
+        };
+
+        let Some((width, height)) = dimensions else {
+            self.remove(&key);
+            return None;
+        };
 
         let thumbnail = Thumbnail {
-            width: header.width as u32,
-            height: header.height as u32,
+            width,
+            height,
             pixels: data,
         };
 
@@ -51,25 +141,61 @@ impl Cache {
     }
 
     pub fn store(&self, path: &Path, thumbnail: &Thumbnail) {
-        if let Some(cached_path) = self.file_hash(path) {
-            if let Some(parent) = cached_path.parent() {
-                if std::fs::create_dir_all(parent).is_err() {
-                    return;
-                }
+        let key = self.key(path);
+        match &*self.store {
+            Store::Files(cache_dir) => {
+                let _ = store_file(&cached_path(cache_dir, &key), &thumbnail.pixels);
+            }
+
+            Store::Packed(packed) => {
+                let _ = packed.store(&key, &thumbnail.pixels);
             }
+        }
+    }
+
+    fn remove(&self, key: &[u8]) {
+        match &*self.store {
+            Store::Files(cache_dir) => {
+                let _ = std::fs::remove_file(cached_path(cache_dir, key));
+            }
+
+            Store::Packed(packed) => {
+                let _ = packed.remove(key);
+            }
+        }
+    }
 
-            let _ = OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(cached_path)
-                .and_then(|mut file| file.write_all(&thumbnail.pixels));
+    /// Store the full image of `path` in the shared thumbnails, if they are
+    /// writable.
+    pub fn store_shared(&self, path: &Path, image: &DynamicImage) {
+        if let Some(shared) = &self.shared {
+            shared.store(path, image);
         }
     }
 
-    fn file_hash(&self, path: &Path) -> Option<PathBuf> {
+    /// Compute the key to identify the thumbnail of a file.
+    fn key(&self, path: &Path) -> Vec<u8> {
         let mut hash = Sha224::new();
 
-        hash.update(self.thumbnail_size.to_ne_bytes());
+        hash.update(self.options.height.to_ne_bytes());
+        hash.update(self.options.width.to_ne_bytes());
+
+        match self.options.background {
+            Background::Transparent => hash.update([0]),
+            Background::Checkerboard => hash.update([1]),
+            Background::Color([r, g, b]) => hash.update([2, r, g, b]),
+        }
+
+        hash.update([u8::from(self.options.apply_orientation)]);
+        hash.update(self.options.document_page.to_ne_bytes());
+
+        if let Some(profile) = self.options.output_profile {
+            hash.update(&profile.data);
+        }
+
+        let tonemap = self.options.tonemap;
+        hash.update([tonemap.operator as u8, u8::from(tonemap.auto_exposure)]);
+        hash.update(tonemap.exposure.to_ne_bytes());
 
         if let Ok(metadata) = std::fs::metadata(path) {
             // Build a hash using data from the metadata.
@@ -82,8 +208,54 @@ impl Cache {
             hash.update(path.as_os_str().as_bytes());
         }
 
-        let hash = hex::encode(hash.finalize());
-        let (prefix, filename) = hash.split_at(2);
-        Some(self.cache_dir.join(prefix).join(filename))
+        hash.finalize().to_vec()
     }
 }
+
+/// Width and height of a complete JPEG image.
+fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if !data.ends_with(JPEG_EOI) {
+        return None;
+    }
+
+    let header = turbojpeg::read_header(data).ok()?;
+    Some((header.width as u32, header.height as u32))
+}
+
+/// Width and height of a complete PNG image.
+fn png_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if !data.ends_with(PNG_IEND) {
+        return None;
+    }
+
+    let reader = png::Decoder::new(data).read_info().ok()?;
+    let info = reader.info();
+    Some((info.width, info.height))
+}
+
+/// Write a thumbnail in the files store.
+///
+/// The data is written to a temporary file, which is renamed when it is
+/// complete, so a process killed in the middle of the write can't leave a
+/// partial thumbnail.
+fn store_file(cached_path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let parent = match cached_path.parent() {
+        Some(parent) => parent,
+        None => return Ok(()),
+    };
+
+    std::fs::create_dir_all(parent)?;
+
+    let mut file = tempfile::NamedTempFile::new_in(parent)?;
+    file.write_all(data)?;
+    file.persist(cached_path)?;
+
+    Ok(())
+}
+
+/// Path of a thumbnail in the files store.
+fn cached_path(cache_dir: &Path, key: &[u8]) -> PathBuf {
+    let hash = hex::encode(key);
+    let (prefix, filename) = hash.split_at(2);
+    cache_dir.join(prefix).join(filename)
+}
@@ -0,0 +1,290 @@
+//! Share thumbnails with other programs, following the [freedesktop.org
+//! Thumbnail Managing Standard][spec].
+//!
+//! [spec]: https://specifications.freedesktop.org/thumbnail-spec/latest/
+
+use std::fmt::Write as _;
+use std::fs::{self, DirBuilder};
+use std::io::BufWriter;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use image::{DynamicImage, ImageFormat};
+use md5::{Digest, Md5};
+
+use crate::images::tonemap::Tonemap;
+use crate::images::{self, Options, Thumbnail};
+
+/// Directories defined by the specification, and the maximum size of the
+/// thumbnails stored in them.
+const FLAVORS: &[(&str, u32)] = &[
+    ("normal", 128),
+    ("large", 256),
+    ("x-large", 512),
+    ("xx-large", 1024),
+];
+
+/// Keys for the PNG `tEXt` chunks.
+const KEY_URI: &str = "Thumb::URI";
+const KEY_MTIME: &str = "Thumb::MTime";
+const KEY_SIZE: &str = "Thumb::Size";
+const KEY_SOFTWARE: &str = "Software";
+
+#[derive(Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum Mode {
+    /// Don't use the shared thumbnails.
+    Off,
+
+    /// Use existing thumbnails, but don't create new ones.
+    Read,
+
+    /// Use existing thumbnails, and store the ones generated by this program.
+    ReadWrite,
+}
+
+pub struct SharedThumbnails {
+    /// Directory for the flavor used by this instance.
+    flavor_dir: PathBuf,
+
+    /// Maximum size of the thumbnails in `flavor_dir`.
+    flavor_size: u32,
+
+    writable: bool,
+
+    options: Options,
+}
+
+impl SharedThumbnails {
+    /// Use the smallest flavor that can contain the thumbnails described by
+    /// `options`.
+    ///
+    /// Shared thumbnails are always rotated according to the orientation of
+    /// the image, their colors are in sRGB, and HDR images use the default
+    /// tonemapping, so they are not used if any of these is changed.
+    pub fn new(mode: Mode, options: Options) -> Option<Self> {
+        if mode == Mode::Off
+            || !options.apply_orientation
+            || options.output_profile.is_some()
+            || options.tonemap != Tonemap::default()
+        {
+            return None;
+        }
+
+        let (flavor, flavor_size) = FLAVORS
+            .iter()
+            .find(|(_, size)| *size >= options.height.max(options.width))?;
+
+        let flavor_dir = dirs::cache_dir()?.join("thumbnails").join(flavor);
+
+        Some(SharedThumbnails {
+            flavor_dir,
+            flavor_size: *flavor_size,
+            writable: mode == Mode::ReadWrite,
+            options,
+        })
+    }
+
+    /// Load a shared thumbnail, if it is still valid for `path`.
+    pub fn get(&self, path: &Path) -> Option<Thumbnail> {
+        let metadata = fs::metadata(path).ok()?;
+        let uri = file_uri(path);
+
+        let data = fs::read(self.thumbnail_path(&uri)).ok()?;
+
+        // Validate the thumbnail with the metadata in its text chunks.
+        let decoder = png::Decoder::new(&data[..]);
+        let reader = decoder.read_info().ok()?;
+
+        let mut valid_uri = false;
+        let mut valid_mtime = false;
+        for chunk in &reader.info().uncompressed_latin1_text {
+            match chunk.keyword.as_str() {
+                KEY_URI => valid_uri = chunk.text == uri,
+                KEY_MTIME => valid_mtime = chunk.text == metadata.mtime().to_string(),
+                _ => (),
+            }
+        }
+
+        if !valid_uri || !valid_mtime {
+            return None;
+        }
+
+        let image = image::load_from_memory_with_format(&data, ImageFormat::Png).ok()?;
+        images::thumbnail(image, &self.options).ok()
+    }
+
+    /// Store a thumbnail for `path`, generated from its full image.
+    ///
+    /// Errors are ignored, since the shared thumbnails are only an
+    /// optimization.
+    pub fn store(&self, path: &Path, image: &DynamicImage) {
+        if !self.writable || path.starts_with(&self.flavor_dir) {
+            return;
+        }
+
+        let _ = self.write(path, image);
+    }
+
+    fn write(&self, path: &Path, image: &DynamicImage) -> anyhow::Result<()> {
+        let metadata = fs::metadata(path)?;
+        let uri = file_uri(path);
+
+        // The specification requires private permissions for the directory.
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.flavor_dir)?;
+
+        let image = if image.width().max(image.height()) > self.flavor_size {
+            image.thumbnail(self.flavor_size, self.flavor_size)
+        } else {
+            image.clone()
+        };
+
+        let image = &images::tonemap::apply(image, &self.options.tonemap);
+
+        // Keep the alpha channel only if the image has one.
+        let (color_type, pixels) = if image.color().has_alpha() {
+            (png::ColorType::Rgba, image.to_rgba8().into_raw())
+        } else {
+            (png::ColorType::Rgb, image.to_rgb8().into_raw())
+        };
+
+        // Write to a temporary file (created with 0600 permissions) in the
+        // same directory, and then rename it, so other programs never see
+        // an incomplete thumbnail.
+        let mut file = tempfile::NamedTempFile::new_in(&self.flavor_dir)?;
+
+        let mut encoder = png::Encoder::new(
+            BufWriter::new(file.as_file_mut()),
+            image.width(),
+            image.height(),
+        );
+
+        encoder.set_color(color_type);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.add_text_chunk(KEY_URI.into(), uri.clone())?;
+        encoder.add_text_chunk(KEY_MTIME.into(), metadata.mtime().to_string())?;
+        encoder.add_text_chunk(KEY_SIZE.into(), metadata.len().to_string())?;
+        encoder.add_text_chunk(KEY_SOFTWARE.into(), env!("CARGO_PKG_NAME").into())?;
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&pixels)?;
+        writer.finish()?;
+
+        file.persist(self.thumbnail_path(&uri))?;
+
+        Ok(())
+    }
+
+    fn thumbnail_path(&self, uri: &str) -> PathBuf {
+        self.flavor_dir.join(thumbnail_name(uri))
+    }
+}
+
+/// Name of the thumbnail file for a URI: the MD5 hash of the URI, in
+/// hexadecimal.
+fn thumbnail_name(uri: &str) -> String {
+    hex::encode(Md5::digest(uri.as_bytes())) + ".png"
+}
+
+/// Build the `file://` URI for an absolute path, escaping bytes in the same
+/// way as GLib, so hashes match the ones computed by other programs.
+pub fn file_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+
+    for &byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'a'..=b'z'
+            | b'A'..=b'Z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'$'
+            | b'&'
+            | b'\''
+            | b'('
+            | b')'
+            | b'*'
+            | b'+'
+            | b','
+            | b'-'
+            | b'.'
+            | b'/'
+            | b':'
+            | b'='
+            | b'@'
+            | b'_'
+            | b'~' => uri.push(char::from(byte)),
+
+            _ => {
+                let _ = write!(uri, "%{:02X}", byte);
+            }
+        }
+    }
+
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    /// Expected URIs are the ones generated by GLib (`gio info`).
+    fn check(path: &Path, uri: &str, name: &str) {
+        assert_eq!(file_uri(path), uri);
+        assert_eq!(thumbnail_name(uri), name);
+    }
+
+    #[test]
+    fn spec_example() {
+        check(
+            Path::new("/home/jens/photos/me.png"),
+            "file:///home/jens/photos/me.png",
+            "c6ee772d9e49320e97ec29a7eb5b1697.png",
+        );
+    }
+
+    #[test]
+    fn spaces() {
+        check(
+            Path::new("/tmp/urit/My Photos/img 01.jpg"),
+            "file:///tmp/urit/My%20Photos/img%2001.jpg",
+            "189aaf2c690f1d4c3ac956eec212b108.png",
+        );
+    }
+
+    #[test]
+    fn non_ascii() {
+        check(
+            Path::new("/tmp/urit/señal ñandú.png"),
+            "file:///tmp/urit/se%C3%B1al%20%C3%B1and%C3%BA.png",
+            "857a51aa87c69b079313768bbb8ae431.png",
+        );
+    }
+
+    #[test]
+    fn escaped_reserved_characters() {
+        check(
+            Path::new("/tmp/urit/a#b?c;d%e[f]\"g.png"),
+            "file:///tmp/urit/a%23b%3Fc%3Bd%25e%5Bf%5D%22g.png",
+            "ea81517247fccdea86b6eabd49bd461d.png",
+        );
+    }
+
+    #[test]
+    fn unescaped_reserved_characters() {
+        check(
+            Path::new("/tmp/urit/!$&'()*+,=:@~.png"),
+            "file:///tmp/urit/!$&'()*+,=:@~.png",
+            "449e9c1f89cc14b9bf10964feed8a27b.png",
+        );
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let path = Path::new(OsStr::from_bytes(b"/tmp/\xFF\xFE.png"));
+        assert_eq!(file_uri(path), "file:///tmp/%FF%FE.png");
+    }
+}
@@ -0,0 +1,65 @@
+//! Store all cached thumbnails in a single database file.
+
+use std::path::Path;
+
+use redb::{Database, Durability, TableDefinition};
+
+/// Table to map hashes (from `Cache::key`) to thumbnails.
+const THUMBNAILS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("thumbnails");
+
+pub struct PackedStore {
+    db: Database,
+}
+
+impl PackedStore {
+    /// Open the database, or create it if it does not exist.
+    ///
+    /// The database can be used only by a single process, so it returns
+    /// `None` if it is already open by another one.
+    pub fn open(path: &Path) -> Option<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).ok()?;
+        }
+
+        let db = Database::create(path).ok()?;
+        Some(PackedStore { db })
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let txn = self.db.begin_read().ok()?;
+        let table = txn.open_table(THUMBNAILS).ok()?;
+        let value = table.get(key).ok()??;
+        Some(value.value().to_vec())
+    }
+
+    /// Add a new entry. Every entry is written in its own transaction, so
+    /// the database always contains complete thumbnails.
+    pub fn store(&self, key: &[u8], data: &[u8]) -> anyhow::Result<()> {
+        let mut txn = self.db.begin_write()?;
+
+        // A lost thumbnail after a system crash is not an issue, so we don't
+        // need to wait for the data to be persisted.
+        txn.set_durability(Durability::Eventual);
+
+        {
+            let mut table = txn.open_table(THUMBNAILS)?;
+            table.insert(key, data)?;
+        }
+
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &[u8]) -> anyhow::Result<()> {
+        let mut txn = self.db.begin_write()?;
+        txn.set_durability(Durability::Eventual);
+
+        {
+            let mut table = txn.open_table(THUMBNAILS)?;
+            table.remove(key)?;
+        }
+
+        txn.commit()?;
+        Ok(())
+    }
+}
@@ -1,14 +1,22 @@
 mod archives;
+mod command;
+mod documents;
 mod ffmpeg;
 mod images;
 mod imgcache;
 mod render;
+mod sniff;
 mod term;
+mod thumbnailers;
+mod warm;
 
+use anyhow::Context;
 use clap::Parser;
+use crossbeam_channel::TrySendError;
 use images::{Source, Thumbnail};
 
-use std::path::PathBuf;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 #[derive(Parser)]
@@ -22,21 +30,141 @@ pub struct Args {
     no_hyperlinks: bool,
 
     /// Images to render.
+    ///
+    /// The members of an archive can be selected by their position, like
+    /// `book.cbz:1-10` or `book.cbz:5`, starting at 1. Members are sorted by
+    /// their names, in natural order. Only the members matching
+    /// `--archive-filter` and with a supported file type are numbered.
     images: Vec<PathBuf>,
 
     /// Color to set foreground for hyperlinks.
     #[clap(short = 'c', long, value_parser = parse_color, default_value = "FF7700")]
     hyperlink_color: [u8; 3],
 
+    /// Background for images with transparency.
+    ///
+    /// It can be a color (as RRGGBB), "checkerboard", or "transparent". With
+    /// "transparent", thumbnails are sent as PNG images.
+    #[clap(short = 'b', long, value_parser = parse_background, default_value = "transparent")]
+    background: images::Background,
+
+    /// Don't rotate or flip images according to their EXIF orientation.
+    #[clap(long)]
+    ignore_orientation: bool,
+
+    /// ICC profile of the display. The colors of the images are converted
+    /// to it.
+    ///
+    /// By default, images are converted to sRGB.
+    #[clap(long, value_parser = parse_icc_profile)]
+    icc_profile: Option<&'static images::icc::OutputProfile>,
+
+    /// Operator to tonemap images with 16-bit or floating-point samples,
+    /// like OpenEXR or Radiance HDR files.
+    #[clap(long, value_enum, default_value = "reinhard")]
+    tonemap: images::tonemap::Operator,
+
+    /// Exposure compensation, in EV, for tonemapped images.
+    #[clap(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    exposure: f32,
+
+    /// Compute the exposure of tonemapped images from their histogram.
+    #[clap(long)]
+    auto_exposure: bool,
+
+    /// Page to render from documents, like PDF files.
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    document_page: u32,
+
     /// Maximum file size to try to read.
     #[clap(short = 'm', long, value_parser = parse_size)]
     max_file_size: Option<u64>,
 
+    /// Maximum size of the videos to read from archives. Videos are read
+    /// in memory before sending them to ffmpeg, so every worker can use up
+    /// to this size.
+    #[clap(long, value_parser = parse_size, default_value = "64MiB")]
+    max_video_size: u64,
+
+    /// Maximum size of all the files to read from an archive.
+    #[clap(long, value_parser = parse_size, default_value = "1GiB")]
+    max_archive_size: u64,
+
+    /// Render only the archive members with a name matching this glob
+    /// pattern, like '*.png'.
+    #[clap(long)]
+    archive_filter: Option<glob::Pattern>,
+
+    /// Password for encrypted archives. It can be used multiple times.
+    #[clap(long)]
+    archive_password: Vec<String>,
+
+    /// File with passwords for encrypted archives, one per line.
+    #[clap(long)]
+    archive_password_file: Option<PathBuf>,
+
+    /// Ask for a password for encrypted archives before rendering the
+    /// images.
+    #[clap(long, conflicts_with = "warm")]
+    ask_archive_password: bool,
+
+    /// Maximum number of nested archives to open inside an archive.
+    #[clap(long, default_value_t = 2)]
+    max_archive_depth: u32,
+
+    /// Maximum number of pixels of the images to decode.
+    #[clap(long)]
+    max_pixels: Option<u64>,
+
+    /// Maximum memory for the pixels of the images being decoded, shared
+    /// by all jobs.
+    #[clap(long, value_parser = parse_size, default_value = "1GiB")]
+    max_memory: u64,
+
+    /// Use the thumbnailers installed in the system (in
+    /// $XDG_DATA_DIRS/thumbnailers), in addition to the ones in the
+    /// configuration directory of this program.
+    #[clap(long)]
+    system_thumbnailers: bool,
+
     /// Number of jobs to run simultaneously.
     ///
     /// By default, it uses the number of CPU available.
     #[clap(short = 'j', long)]
     jobs: Option<usize>,
+
+    /// Use the thumbnails shared with other programs, in the directories
+    /// defined by the freedesktop.org specification.
+    #[clap(long, value_enum, default_value = "read")]
+    shared_thumbnails: imgcache::freedesktop::Mode,
+
+    /// How to store the thumbnails in the cache.
+    ///
+    /// The packed store uses a single file, which is faster on network file
+    /// systems, but it can be used only by one process at a time. If it is
+    /// used by another process, thumbnails are stored in files.
+    #[clap(long, value_enum, default_value = "files")]
+    cache_store: imgcache::StoreKind,
+
+    /// Fill the cache for the images in the given paths, instead of
+    /// rendering them. Directories are walked recursively.
+    ///
+    /// The terminal is not used in this mode, so the size of the cells must
+    /// be set with --cell-size.
+    #[clap(long, requires = "cell_size")]
+    warm: bool,
+
+    /// Sizes, in cells, of the thumbnails generated in --warm mode.
+    ///
+    /// By default, it uses the value of --thumbnail-size.
+    #[clap(long, value_delimiter = ',')]
+    warm_sizes: Vec<u32>,
+
+    /// Size, in pixels, of a cell in the terminal, as WIDTHxHEIGHT.
+    ///
+    /// Used only in --warm mode.
+    #[clap(long, value_parser = parse_cell_size)]
+    cell_size: Option<(u32, u32)>,
 }
 
 fn parse_color(value: &str) -> Result<[u8; 3], &'static str> {
@@ -50,53 +178,159 @@ fn parse_color(value: &str) -> Result<[u8; 3], &'static str> {
     Err("Expected RRGGBB in hexadecimal digits.")
 }
 
+fn parse_background(value: &str) -> Result<images::Background, &'static str> {
+    match value {
+        "transparent" => Ok(images::Background::Transparent),
+        "checkerboard" => Ok(images::Background::Checkerboard),
+        _ => parse_color(value).map(images::Background::Color),
+    }
+}
+
+fn parse_cell_size(value: &str) -> Result<(u32, u32), &'static str> {
+    if let Some((width, height)) = value.split_once('x') {
+        if let (Ok(width), Ok(height)) = (width.parse(), height.parse()) {
+            return Ok((width, height));
+        }
+    }
+
+    Err("Expected WIDTHxHEIGHT.")
+}
+
+fn parse_icc_profile(value: &str) -> Result<&'static images::icc::OutputProfile, String> {
+    // The profile is shared by every thumbnail until the program exits.
+    match images::icc::OutputProfile::load(value.as_ref()) {
+        Ok(profile) => Ok(Box::leak(Box::new(profile))),
+        Err(e) => Err(format!("{e:#}")),
+    }
+}
+
 fn parse_size(value: &str) -> Result<u64, String> {
     let bs: bytesize::ByteSize = value.parse()?;
     Ok(bs.as_u64())
 }
 
+/// Messages sent by the workers for every job.
+enum Message {
+    /// Thumbnail of a file, or the error to generate it.
+    Result(PathBuf, anyhow::Result<Thumbnail>),
+
+    /// Channel for the results of an archive member, which is processed by
+    /// another worker. The results are received in the order of the members.
+    Member(crossbeam_channel::Receiver<Message>),
+}
+
+/// Split the range of archive members from an argument, like `book.cbz:1-10`.
+///
+/// If a file exists with the full name, the argument is used as is.
+fn parse_image_arg(arg: &Path) -> anyhow::Result<(PathBuf, Option<RangeInclusive<usize>>)> {
+    let members = || {
+        let (path, range) = arg.to_str()?.rsplit_once(':')?;
+        let range = match range.split_once('-') {
+            Some((start, "")) => start.parse().ok()?..=usize::MAX,
+            Some((start, end)) => start.parse().ok()?..=end.parse().ok()?,
+            None => {
+                let position = range.parse().ok()?;
+                position..=position
+            }
+        };
+
+        Some((PathBuf::from(path), range))
+    };
+
+    match members() {
+        Some((path, range)) if !arg.exists() => {
+            if *range.start() == 0 {
+                anyhow::bail!("{}: positions of archive members start at 1", arg.display());
+            }
+
+            if range.start() > range.end() {
+                anyhow::bail!("{}: range of archive members is empty", arg.display());
+            }
+
+            Ok((path, Some(range)))
+        }
+
+        _ => Ok((arg.to_owned(), None)),
+    }
+}
+
+/// Channel to send the results of a job.
+type ResultSender = crossbeam_channel::Sender<Message>;
+
 struct Job {
     path: PathBuf,
-    tx: crossbeam_channel::Sender<(PathBuf, anyhow::Result<Thumbnail>)>,
+
+    /// Contents of an archive member. If it is `None`, the file in `path`
+    /// is read.
+    data: Option<Vec<u8>>,
+
+    /// Number of archives containing this file.
+    depth: u32,
+
+    /// Positions of the members to render, if the file is an archive.
+    members: Option<RangeInclusive<usize>>,
+
+    options: images::Options,
+
+    cache: Option<Arc<imgcache::Cache>>,
+
+    /// Channel for the result. The receiver is dropped if the job is an
+    /// archive member outside of the selected positions, so errors sending
+    /// to it are ignored.
+    tx: ResultSender,
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
+    if args.warm {
+        return warm::run(&args);
+    }
+
     let term = term::Term::new()?;
 
-    let cache = Arc::new(imgcache::Cache::new(args.thumbnail_size));
+    let options = thumbnail_options(
+        &args,
+        term.cell_width,
+        term.cell_height,
+        args.thumbnail_size,
+    );
 
-    // Launch multiple threads to create the thumbnails.
+    let images = args
+        .images
+        .iter()
+        .map(|arg| parse_image_arg(arg))
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
-    let (pending_tx, pending_rx) = crossbeam_channel::unbounded::<Job>();
+    let cache = new_cache(&args, options);
 
-    for _ in 0..args.jobs.unwrap_or_else(|| num_cpus::get()) {
-        let rx = pending_rx.clone();
-        let cache = Arc::clone(&cache);
-        let thumbnail_size = args.thumbnail_size;
-        std::thread::spawn(move || {
-            while let Ok(job) = rx.recv() {
-                process_job(
-                    job,
-                    Option::as_ref(&cache),
-                    &term,
-                    thumbnail_size,
-                    args.max_file_size,
-                );
-            }
-        });
+    let mut passwords = archive_passwords(&args)?;
+    if args.ask_archive_password {
+        let password = term.read_password("Archive password: ")?;
+        if !password.is_empty() {
+            passwords.push(password);
+        }
     }
 
-    let jobs: Vec<_> = args
-        .images
-        .iter()
-        .map(|file| {
-            let path = PathBuf::from(file);
+    let pending_tx = spawn_workers(&args, passwords);
+
+    let jobs: Vec<_> = images
+        .into_iter()
+        .map(|(path, members)| {
             let path = path.canonicalize().unwrap_or(path);
 
             let (tx, rx) = crossbeam_channel::unbounded();
-            pending_tx.send(Job { path, tx }).unwrap();
+            let job = Job {
+                path,
+                data: None,
+                depth: 0,
+                members,
+                options,
+                cache: cache.clone(),
+                tx,
+            };
+
+            pending_tx.send(job).unwrap();
 
             rx
         })
@@ -108,7 +342,7 @@ fn main() -> anyhow::Result<()> {
     let mut renderer = render::Renderer::new(term, &args);
 
     for job in jobs {
-        while let Ok((path, thumbnail)) = job.recv() {
+        for (path, thumbnail) in receive_results(job) {
             match thumbnail {
                 Ok(img) => renderer.render(&path, &img)?,
                 Err(e) => failed.push((path, e)),
@@ -125,58 +359,279 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn process_job(
-    job: Job,
-    cache: Option<&imgcache::Cache>,
-    term: &term::Term,
+/// Options to generate thumbnails of `thumbnail_size` cells.
+fn thumbnail_options(
+    args: &Args,
+    cell_width: u32,
+    cell_height: u32,
     thumbnail_size: u32,
-    max_file_size: Option<u64>,
-) {
-    // Try to open the file as an archive.
-    if let Ok(archive) = archives::open(&job.path) {
-        for entry in archive {
-            let path = job.path.join(entry.name);
-            render_file(
-                Source::Mem(&entry.data, path),
-                &job.tx,
-                cache,
-                term,
-                thumbnail_size,
-                max_file_size,
-            );
+) -> images::Options {
+    images::Options {
+        height: cell_height * thumbnail_size,
+        width: cell_width * thumbnail_size * 2,
+        background: args.background,
+        apply_orientation: !args.ignore_orientation,
+        document_page: args.document_page,
+        output_profile: args.icc_profile,
+        tonemap: images::tonemap::Tonemap {
+            operator: args.tonemap,
+            exposure: args.exposure,
+            auto_exposure: args.auto_exposure,
+        },
+    }
+}
+
+/// Create the cache for thumbnails generated with `options`.
+fn new_cache(args: &Args, options: images::Options) -> Option<Arc<imgcache::Cache>> {
+    let cache = imgcache::Cache::new(options, args.cache_store, shared_thumbnails(args, options))?;
+
+    Some(Arc::new(cache))
+}
+
+/// Shared thumbnails for `options`, if they are enabled.
+fn shared_thumbnails(
+    args: &Args,
+    options: images::Options,
+) -> Option<imgcache::freedesktop::SharedThumbnails> {
+    imgcache::freedesktop::SharedThumbnails::new(args.shared_thumbnails, options)
+}
+
+/// Receive the results of a job, including the ones from its archive
+/// members.
+fn receive_results(
+    rx: crossbeam_channel::Receiver<Message>,
+) -> impl Iterator<Item = (PathBuf, anyhow::Result<Thumbnail>)> {
+    let mut channels = vec![rx];
+    std::iter::from_fn(move || loop {
+        match channels.last()?.recv() {
+            Ok(Message::Result(path, thumbnail)) => return Some((path, thumbnail)),
+            Ok(Message::Member(rx)) => channels.push(rx),
+            Err(_) => {
+                channels.pop();
+            }
         }
+    })
+}
+
+/// Passwords for encrypted archives, from the command line and the password
+/// file.
+fn archive_passwords(args: &Args) -> anyhow::Result<Vec<String>> {
+    let mut passwords: Vec<_> = args
+        .archive_password
+        .iter()
+        .filter(|p| !p.is_empty())
+        .cloned()
+        .collect();
+
+    if let Some(path) = &args.archive_password_file {
+        let file = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        passwords.extend(file.lines().filter(|l| !l.is_empty()).map(String::from));
+    }
+
+    Ok(passwords)
+}
+
+/// Launch multiple threads to create the thumbnails.
+///
+/// Returns the channel to send jobs to the threads.
+fn spawn_workers(args: &Args, archive_passwords: Vec<String>) -> crossbeam_channel::Sender<Job> {
+    let (pending_tx, pending_rx) = crossbeam_channel::unbounded::<Job>();
 
+    // Archive members are queued with their contents, so the queue is
+    // bounded to limit the memory used by them. Workers take members before
+    // new files.
+    let jobs = args.jobs.unwrap_or_else(num_cpus::get);
+    let (members_tx, members_rx) = crossbeam_channel::bounded::<Job>(jobs);
+
+    let loader = Arc::new(images::Loader {
+        max_file_size: args.max_file_size,
+        max_video_size: args.max_video_size,
+        max_archive_size: args.max_archive_size,
+        max_archive_depth: args.max_archive_depth,
+        archive_filter: args.archive_filter.clone(),
+        archive_passwords,
+        thumbnailers: thumbnailers::Registry::load(args.system_thumbnailers),
+        limits: images::limits::Limits::new(args.max_pixels, args.max_memory),
+    });
+
+    for _ in 0..jobs {
+        let pending_rx = pending_rx.clone();
+        let members_rx = members_rx.clone();
+        let members_tx = members_tx.clone();
+        let loader = Arc::clone(&loader);
+        std::thread::spawn(move || loop {
+            let job = crossbeam_channel::select_biased! {
+                recv(members_rx) -> job => job,
+                recv(pending_rx) -> job => job,
+            };
+
+            match job {
+                Ok(job) => process_job(job, &loader, &members_tx),
+                Err(_) => break,
+            }
+        });
+    }
+
+    pending_tx
+}
+
+/// Generate the thumbnail for a job.
+///
+/// The members of archives are sent as new jobs to `members_tx`, so they are
+/// processed by all workers.
+fn process_job(job: Job, loader: &images::Loader, members_tx: &crossbeam_channel::Sender<Job>) {
+    let kind = match &job.data {
+        Some(data) => sniff::sniff(data),
+        None => match sniff::sniff_file(&job.path) {
+            Ok(kind) => kind,
+            Err(e) => {
+                let _ = job.tx.send(Message::Result(job.path, Err(e.into())));
+                return;
+            }
+        },
+    };
+
+    // Files with a program to render them (like EPUB documents) are not read
+    // as archives. Archive members are always read, since these programs
+    // need a path.
+    if kind == sniff::Kind::Archive && (job.data.is_some() || !loader.renders_archive(&job.path)) {
+        send_members(job, loader, members_tx);
         return;
     }
 
-    let Job { path, tx } = job;
+    let source = match &job.data {
+        Some(data) => Source::Mem(data, job.path),
+        None => Source::Path(job.path),
+    };
+
     render_file(
-        Source::Path(path),
-        &tx,
-        cache,
-        term,
-        thumbnail_size,
-        max_file_size,
+        source,
+        kind,
+        &job.tx,
+        job.cache.as_deref(),
+        &job.options,
+        loader,
     );
 }
 
+/// Send the members of the archive in a job as new jobs.
+fn send_members(job: Job, loader: &images::Loader, members_tx: &crossbeam_channel::Sender<Job>) {
+    if job.depth > loader.max_archive_depth {
+        let e = anyhow::anyhow!("Archive exceeds the maximum nesting depth");
+        let _ = job.tx.send(Message::Result(job.path, Err(e)));
+        return;
+    }
+
+    let limits = archives::Limits {
+        max_entry_size: loader.max_file_size(),
+        max_video_size: loader.max_video_size,
+        max_total_size: loader.max_archive_size,
+    };
+
+    let archive = match job.data {
+        Some(data) => archives::open_memory(data, limits, &loader.archive_passwords),
+        None => archives::open(&job.path, limits, &loader.archive_passwords),
+    };
+
+    let mut archive = match archive {
+        Ok(archive) => archive
+            .with_filter(loader.archive_filter.clone())
+            .with_range(job.members),
+        Err(e) => {
+            let _ = job.tx.send(Message::Result(job.path, Err(e)));
+            return;
+        }
+    };
+
+    // Members are sent to the workers while the archive is read. Their
+    // channels are sent when all of them are known, to receive the results
+    // in natural order.
+    let mut members = Vec::new();
+    let mut error = None;
+
+    for entry in archive.by_ref() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        };
+
+        let path = job.path.join(&entry.name);
+        let (tx, rx) = crossbeam_channel::unbounded();
+        members.push((entry.name, rx));
+
+        // Report the entries skipped because of the limits, or that can't be
+        // read.
+        let data = match entry.data {
+            Ok(data) => data,
+            Err(e) => {
+                let _ = tx.send(Message::Result(path, Err(e)));
+                continue;
+            }
+        };
+
+        let is_video = sniff::sniff(&data) == sniff::Kind::Video;
+
+        let member = Job {
+            path,
+            data: Some(data),
+            depth: job.depth + 1,
+            members: None,
+            options: job.options,
+            cache: job.cache.clone(),
+            tx,
+        };
+
+        // Videos can be much larger than other members, so they are not
+        // queued. They are rendered by this worker.
+        if is_video {
+            process_job(member, loader, members_tx);
+            continue;
+        }
+
+        // If the queue is full, the member is rendered by this worker, so
+        // the archive is not read faster than its members are rendered.
+        if let Err(TrySendError::Full(member)) = members_tx.try_send(member) {
+            process_job(member, loader, members_tx);
+        }
+    }
+
+    // Members before the start of the range are discarded, even if they are
+    // already rendered.
+    members.sort_by(|(a, _), (b, _)| archives::natural_cmp(a, b));
+    for (name, rx) in members {
+        if archive.in_range(&name) {
+            let _ = job.tx.send(Message::Member(rx));
+        }
+    }
+
+    if let Some(e) = error {
+        let _ = job.tx.send(Message::Result(job.path, Err(e)));
+    }
+}
+
 fn render_file(
     source: Source,
-    tx: &crossbeam_channel::Sender<(PathBuf, anyhow::Result<Thumbnail>)>,
+    kind: sniff::Kind,
+    tx: &ResultSender,
     cache: Option<&imgcache::Cache>,
-    term: &term::Term,
-    thumbnail_size: u32,
-    max_file_size: Option<u64>,
+    options: &images::Options,
+    loader: &images::Loader,
 ) {
     let thumbnail = cache
         .and_then(|c| c.get(source.path()).map(Ok))
         .unwrap_or_else(|| {
-            let thumbnail = images::thumbnail(
-                &source,
-                term.cell_height * thumbnail_size,
-                term.cell_width * thumbnail_size * 2,
-                max_file_size,
-            );
+            let thumbnail = loader.load(&source, kind, options).and_then(|loaded| {
+                if let (Some(cache), Source::Path(path)) = (cache, &source) {
+                    cache.store_shared(path, &loaded.image);
+                }
+
+                images::thumbnail(loaded.image, options)
+            });
 
             if let (Some(cache), Ok(thumbnail)) = (cache.as_ref(), &thumbnail) {
                 cache.store(source.path(), thumbnail);
@@ -185,5 +640,5 @@ fn render_file(
             thumbnail
         });
 
-    tx.send((source.into_path_buf(), thumbnail)).unwrap();
+    let _ = tx.send(Message::Result(source.into_path_buf(), thumbnail));
 }
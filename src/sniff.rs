@@ -0,0 +1,154 @@
+//! Identify the kind of a file from its contents, to choose how to generate
+//! its thumbnail.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Bytes needed to identify a file.
+pub const HEAD_SIZE: u64 = 4096;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Kind {
+    Image,
+    Archive,
+    Video,
+    Document,
+    Unknown,
+}
+
+/// Signatures at the beginning of the files.
+const SIGNATURES: &[(&[u8], Kind)] = &[
+    // Images.
+    (b"\xFF\xD8\xFF", Kind::Image),
+    (b"\x89PNG\r\n\x1A\n", Kind::Image),
+    (b"GIF87a", Kind::Image),
+    (b"GIF89a", Kind::Image),
+    (b"BM", Kind::Image),
+    (b"II*\0", Kind::Image),
+    (b"MM\0*", Kind::Image),
+    (b"IIRO", Kind::Image),
+    (b"IIRS", Kind::Image),
+    (b"MMOR", Kind::Image),
+    (b"IIU\0", Kind::Image),
+    (b"FUJIFILMCCD-RAW ", Kind::Image),
+    (b"\0\0\x01\0", Kind::Image),
+    (b"qoif", Kind::Image),
+    (b"v/1\x01", Kind::Image),
+    (b"#?RADIANCE", Kind::Image),
+    (b"#?RGBE", Kind::Image),
+    (b"DDS ", Kind::Image),
+    (b"farbfeld", Kind::Image),
+    // Archives, and compressed files.
+    (b"PK\x03\x04", Kind::Archive),
+    (b"PK\x05\x06", Kind::Archive),
+    (b"Rar!\x1A\x07", Kind::Archive),
+    (b"7z\xBC\xAF\x27\x1C", Kind::Archive),
+    (b"\x1F\x8B", Kind::Archive),
+    (b"BZh", Kind::Archive),
+    (b"\xFD7zXZ\0", Kind::Archive),
+    (b"\x28\xB5\x2F\xFD", Kind::Archive),
+    (b"\x04\x22\x4D\x18", Kind::Archive),
+    (b"LZIP", Kind::Archive),
+    (b"MSCF", Kind::Archive),
+    (b"!<arch>\n", Kind::Archive),
+    (b"070701", Kind::Archive),
+    (b"070702", Kind::Archive),
+    (b"070707", Kind::Archive),
+    // Videos.
+    (b"\x1A\x45\xDF\xA3", Kind::Video),
+    (b"FLV\x01", Kind::Video),
+    (b"\0\0\x01\xBA", Kind::Video),
+    (b"\0\0\x01\xB3", Kind::Video),
+    (b"\x30\x26\xB2\x75\x8E\x66\xCF\x11", Kind::Video),
+    (b"OggS", Kind::Video),
+    // Documents.
+    (b"%PDF-", Kind::Document),
+];
+
+/// Brands in the `ftyp` box of ISO-BMFF files for HEIF images. Other brands
+/// are assumed to be videos.
+const HEIF_BRANDS: &[&[u8]] = &[
+    b"avif", b"avis", b"heic", b"heim", b"heis", b"heix", b"hevc", b"hevx", b"mif1", b"msf1",
+];
+
+/// Offset of the `ustar` signature in tar files.
+const TAR_SIGNATURE_OFFSET: usize = 257;
+
+/// Size of the packets in MPEG transport streams.
+const MPEG_TS_PACKET_SIZE: usize = 188;
+
+/// Identify the kind of a file, from its first bytes.
+pub fn sniff_file(path: &Path) -> io::Result<Kind> {
+    let mut head = Vec::new();
+    File::open(path)?.take(HEAD_SIZE).read_to_end(&mut head)?;
+    Ok(sniff(&head))
+}
+
+/// Identify the kind of a file from its contents. Only the first bytes are
+/// needed.
+pub fn sniff(data: &[u8]) -> Kind {
+    if let Some((_, kind)) = SIGNATURES.iter().find(|(sig, _)| data.starts_with(sig)) {
+        return *kind;
+    }
+
+    // ISO-BMFF files (MP4, QuickTime, HEIF, etc).
+    if data.get(4..8) == Some(b"ftyp") {
+        return if is_heif(data) {
+            Kind::Image
+        } else {
+            Kind::Video
+        };
+    }
+
+    // RIFF files (WebP, AVI).
+    if data.starts_with(b"RIFF") {
+        return match data.get(8..12) {
+            Some(b"WEBP") => Kind::Image,
+            Some(b"AVI ") => Kind::Video,
+            _ => Kind::Unknown,
+        };
+    }
+
+    if data.get(TAR_SIGNATURE_OFFSET..TAR_SIGNATURE_OFFSET + 5) == Some(b"ustar") {
+        return Kind::Archive;
+    }
+
+    if data.first() == Some(&0x47) && data.get(MPEG_TS_PACKET_SIZE) == Some(&0x47) {
+        return Kind::Video;
+    }
+
+    // PNM images start with `P1` to `P7`, followed by a whitespace.
+    if let [b'P', b'1'..=b'7', space, ..] = data {
+        if space.is_ascii_whitespace() {
+            return Kind::Image;
+        }
+    }
+
+    if is_svg(data) {
+        return Kind::Image;
+    }
+
+    Kind::Unknown
+}
+
+/// Returns `true` if the data is an ISO-BMFF file with a HEIF brand.
+pub fn is_heif(data: &[u8]) -> bool {
+    data.get(4..8) == Some(b"ftyp")
+        && data
+            .get(8..12)
+            .is_some_and(|brand| HEIF_BRANDS.contains(&brand))
+}
+
+/// Returns `true` if the data looks like an SVG document.
+pub fn is_svg(data: &[u8]) -> bool {
+    let head = &data[..data.len().min(HEAD_SIZE as usize)];
+    let head = head.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(head);
+    let head = head.trim_ascii_start();
+
+    let is_xml = [&b"<?xml"[..], b"<!DOCTYPE svg", b"<svg"]
+        .iter()
+        .any(|prefix| head.starts_with(prefix));
+
+    is_xml && head.windows(4).any(|w| w == b"<svg")
+}
@@ -16,16 +16,16 @@ pub struct Term {
 // Use stdout to read and write, so the program can work with no stdin.
 const STDIO: RawFd = 1;
 
+/// Disable some local flags of the terminal, until it is dropped.
 struct RawMode(Termios);
 
 impl RawMode {
-    fn new() -> nix::Result<Self> {
+    fn new(flags: LocalFlags) -> nix::Result<Self> {
         let stdout = std::io::stdout();
         let attrs = tcgetattr(&stdout)?;
 
         let mut change = attrs.clone();
-        change.local_flags.remove(LocalFlags::ICANON);
-        change.local_flags.remove(LocalFlags::ECHO);
+        change.local_flags.remove(flags);
 
         tcsetattr(stdout, SetArg::TCSAFLUSH, &change)?;
         Ok(RawMode(attrs))
@@ -46,7 +46,7 @@ impl Term {
             bail!("Not a TTY");
         }
 
-        let term_mode = match RawMode::new() {
+        let term_mode = match RawMode::new(LocalFlags::ICANON | LocalFlags::ECHO) {
             Ok(m) => m,
             Err(_) => bail!("Can't set raw mode"),
         };
@@ -147,4 +147,46 @@ impl Term {
 
         Ok(term)
     }
+
+    /// Ask for a password in the terminal. The typed characters are not
+    /// shown.
+    pub fn read_password(&self, prompt: &str) -> anyhow::Result<String> {
+        let stdout = std::io::stdout();
+
+        let mut write = prompt.as_bytes();
+        while !write.is_empty() {
+            write = match unistd::write(&stdout, write) {
+                Ok(w) => &write[w..],
+                Err(e) => bail!("Failed to write prompt: {}", e),
+            };
+        }
+
+        let term_mode = match RawMode::new(LocalFlags::ECHO) {
+            Ok(m) => m,
+            Err(_) => bail!("Can't disable echo"),
+        };
+
+        let mut password = Vec::new();
+        while password.last() != Some(&b'\n') {
+            let mut data = [0; 64];
+
+            let read = match unistd::read(STDIO, &mut data) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(Errno::EINTR) => continue,
+                Err(e) => bail!("Failed to read from TTY: {}", e),
+            };
+
+            password.extend_from_slice(&data[..read]);
+        }
+
+        drop(term_mode);
+        let _ = unistd::write(&stdout, b"\n");
+
+        if password.last() == Some(&b'\n') {
+            password.pop();
+        }
+
+        Ok(String::from_utf8(password)?)
+    }
 }
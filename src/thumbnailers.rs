@@ -0,0 +1,325 @@
+//! Registry of external programs to generate thumbnails.
+//!
+//! Thumbnailers are defined in files with the format used by the
+//! freedesktop.org `.thumbnailer` files:
+//!
+//! ```text
+//! [Thumbnailer Entry]
+//! TryExec=blender-thumbnailer
+//! Exec=blender-thumbnailer %i %o
+//! MimeType=application/x-blender;
+//! Extensions=blend;blend1;
+//! ```
+//!
+//! `Extensions` is specific to this program, and it can be used when the MIME
+//! type of the files is not known.
+//!
+//! The MIME type of a file is guessed from its extension, not from its
+//! contents, so files without an extension never match a thumbnailer.
+//!
+//! The `Exec` command can use the following placeholders:
+//!
+//! * `%i`: path of the input file.
+//! * `%u`: URI of the input file.
+//! * `%o`: path of the output file. If it is not present, the command must
+//!   write the image to its standard output.
+//! * `%s`: size, in pixels, of the thumbnail.
+//! * `%%`: a literal `%`.
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::command::{run, Output};
+use crate::imgcache::freedesktop::file_uri;
+
+/// Directory for the thumbnailers, relative to the configuration directory
+/// of the program, or to the XDG data directories.
+const THUMBNAILERS_DIR: &str = "thumbnailers";
+
+/// Extension of the files with the definition of a thumbnailer.
+const THUMBNAILER_EXTENSION: &str = "thumbnailer";
+
+/// Group in the `.thumbnailer` files.
+const THUMBNAILER_GROUP: &str = "[Thumbnailer Entry]";
+
+/// Default value for `$XDG_DATA_DIRS`.
+const DEFAULT_DATA_DIRS: &str = "/usr/local/share:/usr/share";
+
+pub struct Thumbnailer {
+    exec: Vec<String>,
+    mime_types: Vec<String>,
+    extensions: Vec<String>,
+
+    /// `true` if it is installed in the system, instead of configured for
+    /// this program.
+    pub system: bool,
+}
+
+#[derive(Default)]
+pub struct Registry {
+    thumbnailers: Vec<Thumbnailer>,
+}
+
+impl Registry {
+    /// Load the thumbnailers defined in the configuration directory of this
+    /// program.
+    ///
+    /// If `include_system` is `true`, it also loads the thumbnailers
+    /// installed in the system (in `$XDG_DATA_DIRS/thumbnailers`).
+    pub fn load(include_system: bool) -> Registry {
+        let mut dirs = Vec::new();
+
+        if let Some(config_dir) = dirs::config_dir() {
+            dirs.push((
+                config_dir
+                    .join(env!("CARGO_PKG_NAME"))
+                    .join(THUMBNAILERS_DIR),
+                false,
+            ));
+        }
+
+        if include_system {
+            let data_dirs = env::var("XDG_DATA_DIRS")
+                .ok()
+                .filter(|d| !d.is_empty())
+                .unwrap_or_else(|| DEFAULT_DATA_DIRS.to_owned());
+
+            let data_dirs = dirs::data_dir()
+                .into_iter()
+                .chain(env::split_paths(&data_dirs))
+                .map(|dir| (dir.join(THUMBNAILERS_DIR), true));
+
+            dirs.extend(data_dirs);
+        }
+
+        let mut thumbnailers = Vec::new();
+        for (dir, system) in dirs {
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+
+            let mut paths: Vec<_> = entries
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|e| e == THUMBNAILER_EXTENSION))
+                .collect();
+
+            paths.sort();
+
+            for path in paths {
+                match Thumbnailer::load(&path, system) {
+                    Ok(Some(thumbnailer)) => thumbnailers.push(thumbnailer),
+                    Ok(None) => (),
+                    Err(e) => eprintln!("{}: {}", path.display(), e),
+                }
+            }
+        }
+
+        Registry { thumbnailers }
+    }
+
+    /// Find the first thumbnailer that accepts the file, by its MIME type
+    /// or by its extension.
+    ///
+    /// The MIME types are guessed from the extension, so a file with a wrong
+    /// extension is sent to the thumbnailer for that extension.
+    pub fn find(&self, path: &Path) -> Option<&Thumbnailer> {
+        if self.thumbnailers.is_empty() {
+            return None;
+        }
+
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        let mime_types: Vec<_> = mime_guess::from_ext(&extension).iter().collect();
+
+        self.thumbnailers.iter().find(|thumbnailer| {
+            thumbnailer.extensions.contains(&extension)
+                || mime_types.iter().any(|mime| {
+                    thumbnailer
+                        .mime_types
+                        .iter()
+                        .any(|pattern| mime_matches(pattern, mime.essence_str()))
+                })
+        })
+    }
+}
+
+impl Thumbnailer {
+    /// Parse a `.thumbnailer` file.
+    ///
+    /// Returns `None` if the program in `TryExec` is not available.
+    fn load(path: &Path, system: bool) -> anyhow::Result<Option<Thumbnailer>> {
+        let contents = fs::read_to_string(path)?;
+
+        let mut in_group = false;
+        let mut exec = None;
+        let mut try_exec = None;
+        let mut mime_types = Vec::new();
+        let mut extensions = Vec::new();
+
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                in_group = line == THUMBNAILER_GROUP;
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+
+            if !in_group {
+                continue;
+            }
+
+            let list = || {
+                value
+                    .split(';')
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty())
+                    .map(str::to_ascii_lowercase)
+            };
+
+            match key.trim() {
+                "Exec" => exec = Some(split_exec(value.trim())),
+                "TryExec" => try_exec = Some(value.trim().to_owned()),
+                "MimeType" => mime_types.extend(list()),
+                "Extensions" => extensions.extend(list()),
+                _ => (),
+            }
+        }
+
+        let exec = match exec {
+            Some(exec) if !exec.is_empty() => exec,
+            _ => anyhow::bail!("Missing Exec key"),
+        };
+
+        if let Some(try_exec) = try_exec {
+            if find_program(&try_exec).is_none() {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(Thumbnailer {
+            exec,
+            mime_types,
+            extensions,
+            system,
+        }))
+    }
+
+    /// Run the thumbnailer, and return the generated image.
+    pub fn run(&self, path: &Path, size: u32) -> anyhow::Result<Vec<u8>> {
+        let output_dir = tempfile::tempdir()?;
+        let output_path = output_dir.path().join("thumbnail.png");
+
+        let mut uses_output = false;
+        let args: Vec<String> = self
+            .exec
+            .iter()
+            .map(|arg| {
+                expand(arg, |code| match code {
+                    'i' => Some(path.display().to_string()),
+                    'u' => Some(file_uri(path)),
+                    's' => Some(size.to_string()),
+                    'o' => {
+                        uses_output = true;
+                        Some(output_path.display().to_string())
+                    }
+                    '%' => Some("%".to_owned()),
+                    _ => None,
+                })
+            })
+            .collect();
+
+        let output = if uses_output {
+            Output::File(&output_path)
+        } else {
+            Output::Stdout
+        };
+
+        run(Command::new(&args[0]).args(&args[1..]), output)
+    }
+}
+
+/// Compare a MIME type with a pattern, which can use a wildcard for the
+/// subtype, like `image/*`.
+fn mime_matches(pattern: &str, mime: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(prefix) => mime.split('/').next() == Some(prefix),
+        None => pattern == mime,
+    }
+}
+
+/// Split the `Exec` value in arguments. Arguments can be quoted with double
+/// quotes, and a backslash can be used to escape characters.
+fn split_exec(value: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = None::<String>;
+    let mut quoted = false;
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.get_or_insert_with(String::new);
+            }
+
+            '\\' => {
+                if let Some(c) = chars.next() {
+                    current.get_or_insert_with(String::new).push(c);
+                }
+            }
+
+            c if c.is_whitespace() && !quoted => args.extend(current.take()),
+
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    args.extend(current);
+    args
+}
+
+/// Replace the `%x` placeholders in an argument.
+fn expand(arg: &str, mut value: impl FnMut(char) -> Option<String>) -> String {
+    let mut expanded = String::with_capacity(arg.len());
+    let mut chars = arg.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some(code) => expanded.extend(value(code)),
+            None => expanded.push('%'),
+        }
+    }
+
+    expanded
+}
+
+/// Find a program in `$PATH`.
+fn find_program(name: &str) -> Option<PathBuf> {
+    let is_executable = |path: &Path| {
+        fs::metadata(path)
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+
+    if name.contains('/') {
+        let path = PathBuf::from(name);
+        return is_executable(&path).then_some(path);
+    }
+
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| is_executable(path))
+}
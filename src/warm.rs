@@ -0,0 +1,137 @@
+//! Fill the cache without a terminal.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+
+use crate::imgcache::Cache;
+use crate::{Args, Job};
+
+/// Values for `ioprio_set(2)`.
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+/// Nice value for the process.
+const NICE_LEVEL: libc::c_int = 19;
+
+pub fn run(args: &Args) -> anyhow::Result<()> {
+    let (cell_width, cell_height) = args.cell_size.context("--cell-size is required")?;
+
+    set_low_priority();
+
+    let sizes = if args.warm_sizes.is_empty() {
+        &[args.thumbnail_size][..]
+    } else {
+        &args.warm_sizes[..]
+    };
+
+    // All sizes use the same store, since the packed database can be opened
+    // only once.
+    let mut caches: Vec<(_, Arc<Cache>)> = Vec::with_capacity(sizes.len());
+    for size in sizes {
+        let options = crate::thumbnail_options(args, cell_width, cell_height, *size);
+        let cache = match caches.first() {
+            Some((_, cache)) => {
+                Arc::new(cache.with_options(options, crate::shared_thumbnails(args, options)))
+            }
+            None => crate::new_cache(args, options).context("Cache is not available")?,
+        };
+
+        caches.push((options, cache));
+    }
+
+    let mut files = Vec::new();
+    for path in &args.images {
+        let path = path.canonicalize().unwrap_or_else(|_| path.clone());
+        walk(path, &mut files);
+    }
+
+    let pending_tx = crate::spawn_workers(args, crate::archive_passwords(args)?);
+    let (tx, rx) = crossbeam_channel::unbounded();
+
+    for path in files {
+        for (options, cache) in &caches {
+            let job = Job {
+                path: path.clone(),
+                data: None,
+                depth: 0,
+                members: None,
+                options: *options,
+                cache: Some(Arc::clone(cache)),
+                tx: tx.clone(),
+            };
+
+            pending_tx.send(job).unwrap();
+        }
+    }
+
+    // The channel is closed when all jobs are finished.
+    drop(tx);
+
+    for (path, thumbnail) in crate::receive_results(rx) {
+        if let Err(err) = thumbnail {
+            eprintln!("{}: {}", path.display(), err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Collect the files in `path`, walking directories recursively.
+///
+/// Symbolic links to directories are ignored, to avoid loops.
+fn walk(path: PathBuf, files: &mut Vec<PathBuf>) {
+    if !path.is_dir() {
+        files.push(path);
+        return;
+    }
+
+    let mut entries = match fs::read_dir(&path) {
+        Ok(entries) => entries.filter_map(Result::ok).collect::<Vec<_>>(),
+        Err(err) => {
+            eprintln!("{}: {}", path.display(), err);
+            return;
+        }
+    };
+
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        match entry.file_type() {
+            Ok(ft) if ft.is_dir() => walk(entry.path(), files),
+            Ok(ft) if ft.is_symlink() && is_dir(&entry.path()) => (),
+            _ => files.push(entry.path()),
+        }
+    }
+}
+
+fn is_dir(path: &Path) -> bool {
+    fs::metadata(path).map(|m| m.is_dir()).unwrap_or(false)
+}
+
+/// Reduce the CPU and I/O priority, so the system is still responsive while
+/// the cache is filled.
+///
+/// Threads and child processes inherit the priority, so this function must be
+/// called before launching them.
+fn set_low_priority() {
+    unsafe {
+        libc::setpriority(libc::PRIO_PROCESS, 0, NICE_LEVEL);
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        );
+    }
+}